@@ -3,14 +3,26 @@
 
 use std::num::NonZero;
 use std::path::PathBuf;
+use std::process::{self, ExitCode};
 use std::result::Result::Ok;
 use std::thread::available_parallelism;
+use std::thread::sleep;
 use std::time::Duration;
-use std::{process, thread::sleep};
 
 use anyhow::{Result, anyhow};
-use clap::{Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use log::{LevelFilter, debug};
+use serde_json::json;
+use sysexts_manager_lib::config::{AuthType, Config, ExtensionType, NetworkConfig};
+use sysexts_manager_lib::error::Error;
+use sysexts_manager_lib::lock::Wait;
+use sysexts_manager_lib::plan::Plan;
+
+mod output;
+mod progress;
+
+use output::Output;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = "systemd system extension manager")]
@@ -27,6 +39,14 @@ struct Cli {
     #[arg(long, global = true)]
     root: Option<String>,
 
+    /// Wait for other running instances to finish instead of failing. Waits for at most SECONDS if given.
+    #[arg(long, global = true, num_args = 0..=1, value_name = "SECONDS")]
+    wait: Option<Option<u64>>,
+
+    /// Output format. JSON output follows a stable schema, documented in the README.
+    #[arg(short, long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -37,30 +57,99 @@ enum Command {
     Enable {
         /// The sysext to enable instead of operating on all sysexts
         name: Option<String>,
+        /// Show what would change without changing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
     /// Disable all sysexts, or only one if specified
     Disable {
         /// The sysext to disable instead of operating on all sysexts
         name: Option<String>,
+        /// Show what would change without changing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
-    /// Add configuration for a sysext
+    /// Add configuration for a sysext or confext
     Add {
         /// Name of the sysext
         name: String,
         /// Base URL where the sysext and its SHAS256SUMS file are hosted
         url: String,
+        /// Update policy: follow the latest version or pin a specific version
+        #[arg(short, long, default_value = "latest", value_parser = ["latest", "version"])]
+        kind: String,
+        /// Version to pin the sysext to (requires `--kind version`)
+        #[arg(long, required_if_eq("kind", "version"))]
+        version: Option<String>,
+        /// Add a configuration extension (confext) instead of a sysext
+        #[arg(long, default_value_t = false)]
+        confext: bool,
+        /// Verify the OpenPGP signature of the SHA256SUMS file
+        #[arg(long, default_value_t = false)]
+        verify: bool,
+        /// Keyring to use to verify signatures, from /etc/sysexts-manager/keys/<KEYRING>.gpg
+        #[arg(long, requires = "verify")]
+        keyring: Option<String>,
+        /// Mirror for the base URL, tried in order if the base URL fails. Can be repeated.
+        #[arg(long = "mirror")]
+        mirrors: Vec<String>,
+        /// Credentials for the base URL: credential:<NAME> (systemd credential) or file:<PATH>
+        #[arg(long)]
+        auth: Option<String>,
+        /// Send the credentials as a bearer token or as basic auth (user:password)
+        #[arg(long, default_value = "bearer", value_parser = ["bearer", "basic"], requires = "auth")]
+        auth_type: String,
         /// Override any existing configuration file
         #[arg(short, long, default_value_t = false)]
         force: bool,
+        /// Also download the latest image and enable it
+        #[arg(long, default_value_t = false)]
+        now: bool,
     },
     /// Remove configuration and images for a sysext
     Remove {
         /// Name of the sysext
         name: String,
+        /// Show what would change without changing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
     /// Update all configured sysexts
-    Update {},
-    /// Refresh enabled sysexts
+    Update {
+        /// Remove old images once the update is done
+        #[arg(long, default_value_t = false)]
+        clean: bool,
+        /// How many images to keep per sysext and release when cleaning up
+        #[arg(long, default_value_t = 2, requires = "clean")]
+        keep: usize,
+        /// Download again local images that do not match the published hash
+        #[arg(long, default_value_t = false)]
+        repair: bool,
+        /// Show what would change without changing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Write the changes to this file instead of applying them, to apply them later with `apply`
+        #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+        plan: Option<PathBuf>,
+        /// Enable the new images once the update is done
+        #[arg(long, default_value_t = false, conflicts_with_all = ["dry_run", "plan"])]
+        apply: bool,
+    },
+    /// Remove old images, keeping the newest ones and the ones in use
+    Clean {
+        /// How many images to keep per sysext and release
+        #[arg(long, default_value_t = 2)]
+        keep: usize,
+        /// Show what would change without changing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Apply the changes from a plan written by `update --plan`
+    Apply {
+        /// The plan file
+        plan: PathBuf,
+    },
+    /// Refresh enabled sysexts and confexts
     Refresh {},
     /// Status of sysexts
     Status {},
@@ -71,30 +160,71 @@ enum Command {
 //     /// Override operating system VERSION_ID
 //     version_id: Option<String>,
 // }
-// Clean
 
-fn refresh() -> Result<()> {
-    debug!("Asking systemd-sysusers to refresh enabled sysexts");
+fn refresh(types: &[ExtensionType]) -> Result<()> {
+    // Always refresh sysexts as sysexts-manager is itself a sysext
+    let mut services = vec![ExtensionType::Sysext.service()];
+    if types.contains(&ExtensionType::Confext) {
+        services.push(ExtensionType::Confext.service());
+    }
+    debug!("Asking systemd to refresh enabled extensions: {services:?}");
     let mut cmd = process::Command::new("systemctl");
-    cmd.args(["restart", "systemd-sysext.service"]);
+    cmd.arg("restart").args(services);
     let res = match cmd.status() {
         Ok(s) => {
             if !s.success() {
-                Err(anyhow!("Failed to refresh sysexts"))
+                Err(anyhow!("Failed to refresh extensions"))
             } else {
-                debug!("sysexts successfully refreshed");
+                debug!("Extensions successfully refreshed");
                 Ok(())
             }
         }
-        Err(_e) => Err(anyhow!("Failed to refresh sysexts")),
+        Err(_e) => Err(anyhow!("Failed to refresh extensions")),
     };
     // FIXME
     sleep(Duration::new(1, 0));
     res
 }
 
-fn main() -> Result<()> {
+/// Exit code for a failed command, so that scripts can tell the most common
+/// failures apart. Documented in the README. 2 is used by clap for invalid
+/// arguments.
+fn exit_code(e: &anyhow::Error) -> u8 {
+    let Some(e) = e.downcast_ref::<Error>() else {
+        return 1;
+    };
+    match e {
+        Error::Config(_) | Error::Credentials(_) | Error::Plan(_) => 3,
+        Error::NoConfig(_) => 4,
+        Error::NoImage(_) | Error::InvalidImage(_) => 5,
+        Error::HashMismatch { .. } | Error::Signature(_) => 6,
+        Error::Enabled { .. } | Error::Conflict(_) => 7,
+        Error::Locked(_) => 8,
+        Error::Http { .. } | Error::Network(_) | Error::Remote(_) => 9,
+        Error::NoSpace(_) => 10,
+        _ => 1,
+    }
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    // clap can not make an argument conflict with a specific value of
+    // another one
+    if let Command::Add {
+        kind,
+        version: Some(_),
+        ..
+    } = &cli.command
+    {
+        if kind != "version" {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("the argument '--version' cannot be used with '--kind {kind}'"),
+                )
+                .exit();
+        }
+    }
 
     let level = match cli.verbose {
         0 => LevelFilter::Warn,
@@ -108,7 +238,19 @@ fn main() -> Result<()> {
         .format_timestamp(None)
         .init();
 
-    let mut manager = match cli.root {
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            cli.output.error(&e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    let output = cli.output;
+    let mut manager = match &cli.root {
         None => sysexts_manager_lib::manager::new()?,
         Some(p) => {
             let p = PathBuf::from(p);
@@ -117,11 +259,12 @@ fn main() -> Result<()> {
     };
     manager.load_config()?;
     manager.load_images()?;
-
-    // for deployment
-    // read os-release
-    // find current release (version_id) & architecture & variant (?) to filter sysexts
-    // ostree::rpm_ostree_status()?;
+    manager.set_reporter(Box::new(progress::Reporter::new(output == Output::Json)));
+    manager.set_wait(match cli.wait {
+        None => Wait::No,
+        Some(None) => Wait::Forever,
+        Some(Some(s)) => Wait::Timeout(Duration::from_secs(s)),
+    });
 
     let jobs = if cli.jobs == 0 {
         available_parallelism()
@@ -137,19 +280,164 @@ fn main() -> Result<()> {
         .unwrap();
 
     match &cli.command {
-        Command::Enable { name } => match name {
-            None => manager.enable_all(),
-            Some(n) => manager.enable(n),
-        },
-        Command::Disable { name } => match name {
-            None => manager.disable_all(),
-            Some(n) => manager.disable(n),
-        },
-        Command::Add { name, url, force } => manager.add_sysext(name, "latest", url, force),
-        Command::Remove { name } => manager.remove_sysext(name),
-        Command::Update {} => manager.update(),
+        Command::Enable { name, dry_run } => {
+            if *dry_run {
+                return output.plan(manager.plan_enable(name.as_ref()));
+            }
+            let enabled = match name {
+                None => manager.enable_all()?,
+                Some(n) => vec![manager.enable(n)?],
+            };
+            // The reporter prints the sysexts as they are enabled
+            output.print(json!({ "sysexts": enabled }), || {});
+            Ok(())
+        }
+        Command::Disable { name, dry_run } => {
+            if *dry_run {
+                return output.plan(manager.plan_disable(name.as_ref()));
+            }
+            let disabled = match name {
+                None => manager.disable_all()?,
+                Some(n) => manager.disable(n)?.into_iter().collect(),
+            };
+            // The reporter prints the sysexts as they are disabled
+            output.print(json!({ "sysexts": disabled }), || {});
+            Ok(())
+        }
+        Command::Add {
+            name,
+            url,
+            kind,
+            version,
+            confext,
+            verify,
+            keyring,
+            mirrors,
+            auth,
+            auth_type,
+            force,
+            now,
+        } => {
+            let conf = Config {
+                Name: name.clone(),
+                Kind: kind.clone(),
+                Url: url.clone(),
+                Version: version.clone(),
+                Type: if *confext {
+                    ExtensionType::Confext
+                } else {
+                    ExtensionType::Sysext
+                },
+                Verify: *verify,
+                Keyring: keyring.clone(),
+                Mirrors: mirrors.clone(),
+                Auth: auth.clone(),
+                AuthType: if auth_type == "basic" {
+                    AuthType::Basic
+                } else {
+                    AuthType::Bearer
+                },
+                Network: NetworkConfig::default(),
+            };
+            let added = manager.add_sysext(&conf, force)?;
+            output.text(|| {
+                println!(
+                    "Added configuration for {}: {} ({})",
+                    added.ext_type, added.name, added.url
+                );
+            });
+            let (updated, enabled) = match now {
+                true => {
+                    let mut updated = manager.update_one(name, false)?;
+                    output.text(|| output::print_updated(&updated));
+                    if let Some(e) = updated.iter_mut().find_map(|u| u.error.take()) {
+                        return Err(
+                            anyhow::Error::from(e).context(format!("Failed to download {name}"))
+                        );
+                    }
+                    // The reporter prints the sysext once enabled
+                    (Some(updated), Some(manager.enable(name)?))
+                }
+                false => (None, None),
+            };
+            output.print(
+                json!({ "sysexts": [added], "update": updated, "enable": enabled }),
+                || {},
+            );
+            Ok(())
+        }
+        Command::Remove { name, dry_run } => {
+            if *dry_run {
+                return output.plan(manager.plan_remove(name));
+            }
+            let removed = manager.remove_sysext(name)?;
+            output.print(json!({ "sysexts": removed.as_slice() }), || {
+                if let Some(r) = &removed {
+                    println!(
+                        "Removed configuration and images for {}: {}",
+                        r.ext_type, r.name
+                    );
+                }
+            });
+            Ok(())
+        }
+        Command::Update {
+            clean,
+            keep,
+            repair,
+            dry_run,
+            plan,
+            apply,
+        } => {
+            let changes = || manager.plan_update(*repair, clean.then_some(*keep));
+            if *dry_run {
+                return output.plan(changes());
+            }
+            if let Some(path) = plan {
+                let changes = changes()?;
+                changes.save(path)?;
+                output.print(json!(changes), || {
+                    println!(
+                        "Wrote {} changes to {}",
+                        changes.changes.len(),
+                        path.display()
+                    );
+                });
+                return Ok(());
+            }
+            let updated = manager.update(*repair)?;
+            output.text(|| output::print_updated(&updated));
+            // The reporter prints the sysexts as they are enabled
+            let enabled = match apply {
+                true => Some(manager.enable_all()?),
+                false => None,
+            };
+            let cleaned = match clean {
+                true => Some(manager.clean(*keep)?),
+                false => None,
+            };
+            output.update(updated, enabled.as_deref(), cleaned.as_ref())
+        }
+        Command::Clean { keep, dry_run } => {
+            if *dry_run {
+                return output.plan(manager.plan_clean(*keep));
+            }
+            output.clean(&manager.clean(*keep)?);
+            Ok(())
+        }
+        Command::Apply { plan } => {
+            let plan = Plan::load(plan)?;
+            manager.apply(&plan)?;
+            output.print(json!(plan), || {
+                println!("Applied {} changes", plan.changes.len());
+            });
+            Ok(())
+        }
         // Command::Download { name, version_id } => manager.download(name, version_id),
-        Command::Refresh {} => refresh(),
-        Command::Status {} => manager.status(),
+        Command::Refresh {} => refresh(&manager.extension_types()),
+        Command::Status {} => {
+            output.status(&manager.status()?);
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,153 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+//! Output of the commands: human readable text, or JSON documents with a
+//! stable schema (documented in the README) for scripts.
+
+use std::fmt;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use sysexts_manager_lib::plan::Plan;
+use sysexts_manager_lib::report::{Cleaned, Enabled, Status, UpdateStatus, Updated};
+
+/// Context for the error of a command that already printed its document,
+/// with the details of the failure
+#[derive(Debug)]
+pub struct Reported(String);
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+impl Output {
+    /// Print the result of a command: the JSON document or the text
+    pub fn print(self, json: Value, text: impl FnOnce()) {
+        match self {
+            Output::Text => text(),
+            Output::Json => println!("{json:#}"),
+        }
+    }
+
+    /// Print some text right away, only for the text output
+    pub fn text(self, text: impl FnOnce()) {
+        if self == Output::Text {
+            text();
+        }
+    }
+
+    /// Print the error that made a command fail. Errors are also logged to
+    /// stderr in all cases.
+    pub fn error(self, e: &anyhow::Error) {
+        if self == Output::Json && e.downcast_ref::<Reported>().is_none() {
+            println!("{:#}", json!({ "error": format!("{e:#}") }));
+        }
+    }
+
+    /// Print the changes from a dry run
+    pub fn plan(self, plan: sysexts_manager_lib::error::Result<Plan>) -> Result<()> {
+        let plan = plan?;
+        self.print(json!(plan), || {
+            if plan.is_empty() {
+                println!("No changes");
+            }
+            for change in &plan.changes {
+                println!("{change}");
+            }
+        });
+        Ok(())
+    }
+
+    pub fn status(self, status: &Status) {
+        self.print(json!(status), || {
+            println!("sysexts:");
+            for s in &status.sysexts {
+                match &s.version {
+                    None => println!("  {} ({}, {}, {}):", s.name, s.ext_type, s.kind, s.url),
+                    Some(v) => {
+                        println!("  {} ({}, {} {v}, {}):", s.name, s.ext_type, s.kind, s.url)
+                    }
+                }
+                if s.images.is_empty() {
+                    println!("    No images installed for that sysext");
+                }
+                for i in &s.images {
+                    if s.enabled.as_ref() == Some(&i.image) {
+                        println!("    {} (enabled)", i.image);
+                    } else {
+                        println!("    {}", i.image);
+                    }
+                }
+            }
+            for e in &status.errors {
+                println!("Invalid configuration: {}: {}", e.source.display(), e.error);
+            }
+        });
+    }
+
+    /// Results of each update are printed as soon as they are known. Fails
+    /// with the error of the first sysext that could not be updated, if any.
+    pub fn update(
+        self,
+        updated: Vec<Updated>,
+        enabled: Option<&[Enabled]>,
+        cleaned: Option<&Cleaned>,
+    ) -> Result<()> {
+        let json = json!({ "sysexts": updated, "enable": enabled, "clean": cleaned });
+        let failed: Vec<&Updated> = updated
+            .iter()
+            .filter(|u| u.status == UpdateStatus::Failed)
+            .collect();
+        self.print(json, || {
+            if failed.is_empty() {
+                println!("Successfully updated all sysexts");
+            } else {
+                println!("Some sysexts failed to update:");
+                for u in &failed {
+                    let error = u.error.as_ref().map(|e| e.to_string()).unwrap_or_default();
+                    println!("  {} (VERSION_ID: {}): {error}", u.name, u.version_id);
+                }
+            }
+            if let Some(cleaned) = cleaned {
+                print_cleaned(cleaned);
+            }
+        });
+        let Some((name, e)) = updated
+            .into_iter()
+            .find_map(|u| u.error.map(|e| (u.name, e)))
+        else {
+            return Ok(());
+        };
+        Err(anyhow::Error::from(e).context(Reported(format!("Failed to update {name}"))))
+    }
+
+    pub fn clean(self, cleaned: &Cleaned) {
+        self.print(json!(cleaned), || print_cleaned(cleaned));
+    }
+}
+
+pub fn print_updated(updated: &[Updated]) {
+    for u in updated {
+        match u.status {
+            UpdateStatus::Updated => println!("Successfully updated sysext: {}", u.name),
+            UpdateStatus::UpToDate => println!("No update found for '{}'", u.name),
+            UpdateStatus::Skipped | UpdateStatus::Failed => {}
+        }
+    }
+}
+
+fn print_cleaned(cleaned: &Cleaned) {
+    println!(
+        "Removed {} images, reclaimed {} bytes",
+        cleaned.images, cleaned.reclaimed
+    );
+}
@@ -0,0 +1,286 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+//! Reporting of the manager events. Download progress goes to stderr, so that
+//! it does not get mixed with the output of the commands: as progress bars
+//! when stderr is a terminal and as JSON events, one per line, otherwise or
+//! with the JSON output.
+
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, stderr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::json;
+use sysexts_manager_lib::reporter::{self, Event};
+
+/// Minimum delay between two progress events
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Progress of a single download
+struct Stats {
+    position: u64,
+    total: Option<u64>,
+    /// Position where the download (re)started, to compute the rate
+    start_position: u64,
+    started: Instant,
+}
+
+impl Stats {
+    /// Download rate in bytes per second
+    fn rate(&self) -> u64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0;
+        }
+        ((self.position - self.start_position) as f64 / elapsed) as u64
+    }
+
+    /// Estimated time left in seconds
+    fn eta(&self) -> Option<u64> {
+        let rate = self.rate();
+        if rate == 0 {
+            return None;
+        }
+        self.total
+            .map(|t| t.saturating_sub(self.position).div_ceil(rate))
+    }
+}
+
+/// State of all the downloads running in parallel
+#[derive(Default)]
+struct Downloads {
+    active: BTreeMap<String, Stats>,
+    /// Bytes downloaded by finished downloads
+    finished: u64,
+    last_event: Option<Instant>,
+    /// Progress bar for the total of all downloads, created with the first
+    /// download
+    total_bar: Option<ProgressBar>,
+    /// Progress bar for each active download
+    bars: BTreeMap<String, ProgressBar>,
+}
+
+impl Downloads {
+    /// Emit an event with the progress of all active downloads, at most once
+    /// per EVENT_INTERVAL
+    fn progress_event(&mut self) {
+        if self
+            .last_event
+            .is_some_and(|t| t.elapsed() < EVENT_INTERVAL)
+        {
+            return;
+        }
+        self.last_event = Some(Instant::now());
+        let downloads = self
+            .active
+            .iter()
+            .map(|(name, s)| {
+                json!({
+                    "name": name,
+                    "bytes": s.position,
+                    "total": s.total,
+                    "rate": s.rate(),
+                    "eta": s.eta(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let bytes = self.finished + self.active.values().map(|s| s.position).sum::<u64>();
+        let total = self
+            .active
+            .values()
+            .map(|s| s.total)
+            .sum::<Option<u64>>()
+            .map(|t| t + self.finished);
+        event(json!({
+            "event": "progress",
+            "bytes": bytes,
+            "total": total,
+            "downloads": downloads,
+        }));
+    }
+}
+
+fn event(value: serde_json::Value) {
+    eprintln!("{value}");
+}
+
+/// Report the manager events
+pub struct Reporter {
+    downloads: Mutex<Downloads>,
+    /// Progress bars, only when stderr is a terminal
+    bars: Option<MultiProgress>,
+    /// Only report JSON events, as stdout is reserved for the JSON output
+    json: bool,
+}
+
+impl Reporter {
+    pub fn new(json: bool) -> Reporter {
+        Reporter {
+            downloads: Mutex::new(Downloads::default()),
+            bars: (!json && stderr().is_terminal()).then(MultiProgress::new),
+            json,
+        }
+    }
+
+    /// Print a line without messing with the progress bars
+    fn println(&self, line: &str) {
+        match &self.bars {
+            Some(multi) => multi.suspend(|| println!("{line}")),
+            None => println!("{line}"),
+        }
+    }
+
+    fn download_started(&self, image: &str, position: u64, total: Option<u64>) {
+        let mut downloads = self.downloads.lock().unwrap();
+        let downloads = &mut *downloads;
+        let previous = downloads.active.insert(
+            image.to_string(),
+            Stats {
+                position,
+                total,
+                start_position: position,
+                started: Instant::now(),
+            },
+        );
+        let Some(multi) = &self.bars else {
+            event(json!({
+                "event": "download-started",
+                "name": image,
+                "bytes": position,
+                "total": total,
+            }));
+            return;
+        };
+        let all = downloads.total_bar.get_or_insert_with(|| {
+            let total = multi.add(ProgressBar::new(0));
+            total.set_style(style("Total "));
+            total
+        });
+        let bar = downloads.bars.entry(image.to_string()).or_insert_with(|| {
+            let bar = multi.insert_before(all, ProgressBar::new(0));
+            bar.set_style(style(""));
+            bar.set_message(image.to_string());
+            bar
+        });
+        // Replace what a previous attempt contributed to the total
+        let (previous_position, previous_total) = previous
+            .map(|s| (s.position, s.total.unwrap_or(0)))
+            .unwrap_or((0, 0));
+        all.set_length(
+            all.length().unwrap_or(0).saturating_sub(previous_total) + total.unwrap_or(0),
+        );
+        all.set_position(all.position().saturating_sub(previous_position) + position);
+        if let Some(total) = total {
+            bar.set_length(total);
+        }
+        bar.set_position(position);
+        bar.reset_eta();
+    }
+
+    fn download_progress(&self, image: &str, position: u64) {
+        let mut downloads = self.downloads.lock().unwrap();
+        let Some(stats) = downloads.active.get_mut(image) else {
+            return;
+        };
+        let bytes = position.saturating_sub(stats.position);
+        stats.position = position;
+        if self.bars.is_none() {
+            downloads.progress_event();
+            return;
+        }
+        if let Some(bar) = downloads.bars.get(image) {
+            bar.inc(bytes);
+        }
+        if let Some(all) = &downloads.total_bar {
+            all.inc(bytes);
+        }
+    }
+
+    fn download_finished(&self, image: &str, success: bool) {
+        let mut downloads = self.downloads.lock().unwrap();
+        let bytes = downloads
+            .active
+            .remove(image)
+            .map(|s| s.position)
+            .unwrap_or(0);
+        if success {
+            downloads.finished += bytes;
+        }
+        if self.bars.is_none() {
+            event(json!({
+                "event": "download-finished",
+                "name": image,
+                "success": success,
+                "bytes": bytes,
+            }));
+            return;
+        }
+        let Some(bar) = downloads.bars.remove(image) else {
+            return;
+        };
+        if let Some(all) = downloads.total_bar.as_ref().filter(|_| !success) {
+            all.set_length(
+                all.length()
+                    .unwrap_or(0)
+                    .saturating_sub(bar.length().unwrap_or(0)),
+            );
+            all.set_position(all.position().saturating_sub(bar.position()));
+        }
+        bar.finish_and_clear();
+    }
+}
+
+fn style(prefix: &str) -> ProgressStyle {
+    ProgressStyle::with_template(&format!(
+        "{prefix}{{msg}} [{{bar:30}}] {{bytes}}/{{total_bytes}} {{binary_bytes_per_sec}} ETA {{eta}}"
+    ))
+    .unwrap()
+    .progress_chars("=> ")
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if let Some(all) = &self.downloads.lock().unwrap().total_bar {
+            all.finish_and_clear();
+        }
+    }
+}
+
+impl reporter::Reporter for Reporter {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Enabled(e) if self.json => {
+                let mut value = json!(e);
+                value["event"] = json!("enabled");
+                self::event(value);
+            }
+            Event::Disabled(d) if self.json => {
+                let mut value = json!(d);
+                value["event"] = json!("disabled");
+                self::event(value);
+            }
+            Event::Enabled(e) => self.println(&format!("Enabled {}: {}", e.ext_type, e.name)),
+            Event::Disabled(d) => self.println(&format!("Disabled {}: {}", d.ext_type, d.name)),
+            Event::DownloadStarted {
+                image,
+                position,
+                total,
+            } => self.download_started(image, *position, *total),
+            Event::DownloadProgress {
+                image, position, ..
+            } => self.download_progress(image, *position),
+            Event::DownloadFinished { image, success } => self.download_finished(image, *success),
+            Event::VerificationFailed { image, error } => match &self.bars {
+                Some(_) => self.println(&format!("Verification failed for {image}: {error}")),
+                None => self::event(json!({
+                    "event": "verification-failed",
+                    "name": image,
+                    "error": error.to_string(),
+                })),
+            },
+            _ => {}
+        }
+    }
+}
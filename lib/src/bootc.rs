@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde::Deserialize;
+
+use super::deployment::{self, Backend};
+use super::error::{Error, Result};
+
+/// Representation of the bootc host state; this can be parsed directly from
+/// the output of `bootc status --json`. Only the fields we need are here.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Host {
+    pub status: HostStatus,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostStatus {
+    pub staged: Option<BootEntry>,
+    pub booted: Option<BootEntry>,
+    pub rollback: Option<BootEntry>,
+}
+
+/// A bootable entry, i.e. a deployment of a container image
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootEntry {
+    pub image: Option<ImageStatus>,
+    #[serde(default)]
+    pub pinned: bool,
+    pub ostree: Option<BootEntryOstree>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageStatus {
+    pub image: ImageReference,
+    pub version: Option<String>,
+    pub image_digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageReference {
+    pub image: String,
+    pub transport: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BootEntryOstree {
+    pub checksum: String,
+    pub deploy_serial: u32,
+    pub stateroot: String,
+}
+
+impl Host {
+    /// Parse the output of `bootc status --json`
+    pub fn from_json(json: &[u8]) -> Result<Host> {
+        serde_json::from_slice(json)
+            .map_err(|e| Error::Command(format!("failed to parse 'bootc status' output: {e}")))
+    }
+
+    /// Convert the booted, staged and rollback entries into deployments
+    pub fn to_deployments(&self) -> Vec<deployment::Deployment> {
+        let mut deployments = Vec::new();
+        let entries = [
+            (&self.status.staged, true, false, false),
+            (&self.status.booted, false, true, false),
+            (&self.status.rollback, false, false, true),
+        ];
+        for (entry, staged, booted, rollback) in entries {
+            let Some(entry) = entry else {
+                continue;
+            };
+            // Only ostree based deployments have a checkout that we can read
+            let Some(ostree) = &entry.ostree else {
+                continue;
+            };
+            deployments.push(deployment::Deployment {
+                booted,
+                staged,
+                rollback,
+                pinned: entry.pinned,
+                image: entry.image.as_ref().map(|i| i.image.image.clone()),
+                version: entry.image.as_ref().and_then(|i| i.version.clone()),
+                path: PathBuf::from(format!(
+                    "ostree/deploy/{}/deploy/{}.{}",
+                    ostree.stateroot, ostree.checksum, ostree.deploy_serial
+                )),
+            });
+        }
+        deployments
+    }
+}
+
+pub fn bootc_status() -> Result<Host> {
+    let res = Command::new("bootc").args(["status", "--json"]).output()?;
+
+    if !res.status.success() {
+        return Err(Error::Command(format!(
+            "running 'bootc status' failed: {}",
+            String::from_utf8_lossy(&res.stderr)
+        )));
+    }
+
+    Host::from_json(&res.stdout)
+}
+
+/// bootc deployment backend
+pub struct Bootc {}
+
+impl Backend for Bootc {
+    fn name(&self) -> &'static str {
+        "bootc"
+    }
+
+    fn deployments(&self) -> Result<Vec<deployment::Deployment>> {
+        Ok(bootc_status()?.to_deployments())
+    }
+}
@@ -1,32 +1,287 @@
 // SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
 // SPDX-License-Identifier: MIT
 
-use std::fs;
+use std::fmt;
 use std::path::Path;
 
-use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
 use toml;
 
+use super::credentials;
+use super::error::{Error, Result};
+
+/// Type of extension image: system extensions (sysexts) extend /usr and /opt,
+/// configuration extensions (confexts) extend /etc
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtensionType {
+    #[default]
+    Sysext,
+    Confext,
+}
+
+impl ExtensionType {
+    /// Directory where images are enabled until next boot
+    pub fn runtime_dir(&self) -> &'static str {
+        match self {
+            ExtensionType::Sysext => "run/extensions",
+            ExtensionType::Confext => "run/confexts",
+        }
+    }
+
+    /// All directories from which images may be enabled
+    pub fn extensions_dirs(&self) -> &'static [&'static str] {
+        match self {
+            ExtensionType::Sysext => &["run/extensions", "etc/extensions", "var/lib/extensions"],
+            ExtensionType::Confext => &["run/confexts", "etc/confexts", "var/lib/confexts"],
+        }
+    }
+
+    /// systemd service that merges the enabled images
+    pub fn service(&self) -> &'static str {
+        match self {
+            ExtensionType::Sysext => "systemd-sysext.service",
+            ExtensionType::Confext => "systemd-confext.service",
+        }
+    }
+
+    fn is_sysext(&self) -> bool {
+        *self == ExtensionType::Sysext
+    }
+}
+
+impl fmt::Display for ExtensionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ExtensionType::Sysext => "sysext",
+            ExtensionType::Confext => "confext",
+        };
+        write!(f, "{s}")
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(non_snake_case, dead_code)]
 pub struct Config {
     pub Name: String,
     pub Kind: String,
     pub Url: String,
+    /// Exact version to pin the sysext to. Only used with the "version" Kind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Version: Option<String>,
+    /// Type of extension: "sysext" (default) or "confext"
+    #[serde(default, skip_serializing_if = "ExtensionType::is_sysext")]
+    pub Type: ExtensionType,
+    /// Verify the OpenPGP signature (SHA256SUMS.gpg) of the SHA256SUMS file
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub Verify: bool,
+    /// Name of the keyring used to verify signatures, looked up as
+    /// `keys/<Keyring>.gpg` in the configuration directories. Defaults to
+    /// "default".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Keyring: Option<String>,
+    /// Mirrors of Url, tried in order when downloading from Url fails
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub Mirrors: Vec<String>,
+    /// Credentials for the repository at Url (never sent to mirrors):
+    /// `credential:<name>` for a systemd credential or `file:<path>` for a
+    /// file only readable by its owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Auth: Option<String>,
+    /// How the credentials are sent: "bearer" (default) for a token or
+    /// "basic" for `user:password`
+    #[serde(default, skip_serializing_if = "AuthType::is_bearer")]
+    pub AuthType: AuthType,
+    /// Network settings for this sysext, overriding the global ones
+    #[serde(flatten)]
+    pub Network: NetworkConfig,
+}
+
+/// Authentication scheme used for the credentials of a repository
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthType {
+    #[default]
+    Bearer,
+    Basic,
+}
+
+impl AuthType {
+    fn is_bearer(&self) -> bool {
+        *self == AuthType::Bearer
+    }
+}
+
+/// Network settings used for all requests, set globally and per sysext
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[allow(non_snake_case)]
+pub struct NetworkConfig {
+    /// Proxy URL used for all requests instead of the system proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Proxy: Option<String>,
+    /// Comma separated list of hosts that should not go through the proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub NoProxy: Option<String>,
+    /// Path to a PEM bundle of additional CA certificates to trust
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub CACertificates: Option<String>,
+    /// Path to a PEM client certificate for mTLS. Needs ClientKey.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ClientCertificate: Option<String>,
+    /// Path to the PEM (PKCS#8) private key for ClientCertificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ClientKey: Option<String>,
+}
+
+impl NetworkConfig {
+    /// Use the settings set here, falling back to the ones from `other` for
+    /// the settings that are not set
+    pub fn or(&self, other: &NetworkConfig) -> NetworkConfig {
+        NetworkConfig {
+            Proxy: self.Proxy.clone().or(other.Proxy.clone()),
+            NoProxy: self.NoProxy.clone().or(other.NoProxy.clone()),
+            CACertificates: self.CACertificates.clone().or(other.CACertificates.clone()),
+            ClientCertificate: self
+                .ClientCertificate
+                .clone()
+                .or(other.ClientCertificate.clone()),
+            ClientKey: self.ClientKey.clone().or(other.ClientKey.clone()),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 impl Config {
-    pub fn new(path: &Path) -> Result<Config> {
-        let Ok(f) = fs::read_to_string(path) else {
-            return Err(anyhow!(
-                "Could not read content from file: {}",
+    /// Parse and validate the content of a configuration file. The path is
+    /// only used in error messages.
+    pub fn parse(content: &str, path: &Path) -> Result<Config> {
+        let Ok(c) = toml::from_str::<Config>(content) else {
+            return Err(Error::Config(format!(
+                "Invalid config in file: {}",
                 path.display()
-            ));
-        };
-        let Ok(c) = toml::from_str::<Config>(&f) else {
-            return Err(anyhow!("Invalid config in file: {}", path.display()));
+            )));
         };
+        c.validate()?;
         Ok(c)
     }
+
+    /// All base URLs for the sysext: Url first and then the mirrors
+    pub fn urls(&self) -> Vec<&str> {
+        let mut urls = vec![self.Url.as_str()];
+        urls.extend(self.Mirrors.iter().map(|m| m.as_str()));
+        urls
+    }
+
+    /// Make sure that the settings are consistent: Kind policy is supported and has all the fields it
+    /// needs.
+    pub fn validate(&self) -> Result<()> {
+        if self.Verify && self.Url.starts_with("oci://") {
+            return Err(Error::Config(format!(
+                "Signature verification is not supported for OCI registries: {}",
+                self.Name
+            )));
+        }
+        if let Some(auth) = &self.Auth {
+            if !credentials::is_valid_reference(auth) {
+                return Err(Error::Config(format!(
+                    "Invalid Auth for sysext {} (expected 'credential:<name>' or 'file:<path>'): {auth}",
+                    self.Name
+                )));
+            }
+        }
+        match self.Kind.as_str() {
+            "latest" => match &self.Version {
+                Some(_) => Err(Error::Config(format!(
+                    "Version is only supported with the 'version' Kind: {}",
+                    self.Name
+                ))),
+                None => Ok(()),
+            },
+            "version" => match &self.Version {
+                Some(_) => Ok(()),
+                None => Err(Error::Config(format!(
+                    "Missing Version for sysext with 'version' Kind: {}",
+                    self.Name
+                ))),
+            },
+            k => Err(Error::Config(format!(
+                "Unsupported Kind for sysext {}: {k}",
+                self.Name
+            ))),
+        }
+    }
+}
+
+/// Global settings, shared by all sysexts
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[allow(non_snake_case)]
+#[serde(default)]
+pub struct GlobalConfig {
+    /// How many times failed requests are retried
+    pub Retries: u32,
+    /// Delay in seconds before the first retry. Doubled for each retry.
+    pub RetryDelay: u64,
+    /// Maximum size of the image store, in bytes or with a K, M, G or T
+    /// suffix. Old images are removed to stay below it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub StoreQuota: Option<String>,
+    /// Network settings for all sysexts
+    #[serde(flatten)]
+    pub Network: NetworkConfig,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        GlobalConfig {
+            Retries: 3,
+            RetryDelay: 1,
+            StoreQuota: None,
+            Network: NetworkConfig::default(),
+        }
+    }
+}
+
+impl GlobalConfig {
+    /// Same as `Config::parse` for the global configuration file
+    pub fn parse(content: &str, path: &Path) -> Result<GlobalConfig> {
+        let c = toml::from_str::<GlobalConfig>(content).map_err(|e| {
+            Error::Config(format!("Invalid config in file: {}: {e}", path.display()))
+        })?;
+        c.store_quota().map_err(|e| {
+            Error::Config(format!("Invalid config in file: {}: {e}", path.display()))
+        })?;
+        Ok(c)
+    }
+
+    /// Maximum size of the image store in bytes, if any
+    pub fn store_quota(&self) -> Result<Option<u64>> {
+        self.StoreQuota.as_deref().map(parse_size).transpose()
+    }
+}
+
+/// Parse a size in bytes, optionally with a K, M, G or T suffix (powers of
+/// 1024)
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        u => return Err(Error::Config(format!("Invalid size unit: '{u}'"))),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| Error::Config(format!("Invalid size: '{size}'")))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or(Error::Config(format!("Size is too large: '{size}'")))
 }
@@ -0,0 +1,141 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use cap_std::fs::Dir;
+use log::debug;
+
+use super::error::{Error, Result};
+
+/// Directories where systemd looks for credentials, relative to the root
+/// directory
+const CREDSTORE_DIRS: [&str; 2] = ["run/credstore", "etc/credstore"];
+const CREDSTORE_ENCRYPTED_DIRS: [&str; 2] = ["run/credstore.encrypted", "etc/credstore.encrypted"];
+
+/// Load a secret from a reference:
+/// - `credential:<name>`: systemd credential, looked up in
+///   `$CREDENTIALS_DIRECTORY` (set by systemd for services using
+///   `LoadCredential=`), then in the `credstore` directories, and finally in
+///   the `credstore.encrypted` directories (decrypted with `systemd-creds`)
+/// - `file:<path>`: file that must only be accessible by its owner
+///
+/// Files are read from the root directory of the target system. `rootdir` is
+/// only used in messages. The content of the secret is never logged.
+pub fn load(root: &Dir, rootdir: &Path, reference: &str) -> Result<String> {
+    let secret = if let Some(name) = reference.strip_prefix("credential:") {
+        load_credential(root, rootdir, name)?
+    } else if let Some(path) = reference.strip_prefix("file:") {
+        load_file(root, rootdir, Path::new(path.trim_start_matches('/')))?
+    } else {
+        return Err(Error::Credentials(format!(
+            "Invalid secret reference (expected 'credential:<name>' or 'file:<path>'): {reference}"
+        )));
+    };
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Whether a secret reference uses a supported scheme
+pub fn is_valid_reference(reference: &str) -> bool {
+    reference.starts_with("credential:") || reference.starts_with("file:")
+}
+
+fn load_credential(root: &Dir, rootdir: &Path, name: &str) -> Result<String> {
+    if name.is_empty() || name.contains('/') {
+        return Err(Error::Credentials(format!(
+            "Invalid credential name: '{name}'"
+        )));
+    }
+    if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") {
+        let path = Path::new(&dir).join(name);
+        if path.exists() {
+            debug!("Loading credential from: {}", path.display());
+            return fs::read_to_string(&path).map_err(|e| {
+                Error::Credentials(format!(
+                    "Could not read credential: {}: {e}",
+                    path.display()
+                ))
+            });
+        }
+    }
+    for dir in CREDSTORE_DIRS {
+        let path = Path::new(dir).join(name);
+        if root.exists(&path) {
+            debug!("Loading credential from: {}", rootdir.join(&path).display());
+            return load_file(root, rootdir, &path);
+        }
+    }
+    for dir in CREDSTORE_ENCRYPTED_DIRS {
+        let path = Path::new(dir).join(name);
+        if root.exists(&path) {
+            let path = rootdir.join(&path);
+            debug!("Decrypting credential: {}", path.display());
+            let encrypted = root.read(Path::new(dir).join(name)).map_err(|e| {
+                Error::Credentials(format!(
+                    "Could not read credential: {}: {e}",
+                    path.display()
+                ))
+            })?;
+            return decrypt(name, &encrypted);
+        }
+    }
+    Err(Error::Credentials(format!(
+        "Could not find credential: {name}"
+    )))
+}
+
+/// Read a secret from a file relative to the root directory, refusing files
+/// that are accessible by group or others
+fn load_file(root: &Dir, rootdir: &Path, path: &Path) -> Result<String> {
+    let path_display = rootdir.join(path);
+    let read_error = |e| {
+        Error::Credentials(format!(
+            "Could not read secret: {}: {e}",
+            path_display.display()
+        ))
+    };
+    let mut file = root.open(path).map_err(read_error)?.into_std();
+    let metadata = file.metadata().map_err(read_error)?;
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(Error::Credentials(format!(
+            "Refusing to use secret accessible by group or others (use mode 0600): {}",
+            path_display.display()
+        )));
+    }
+    let mut secret = String::new();
+    file.read_to_string(&mut secret).map_err(read_error)?;
+    Ok(secret)
+}
+
+/// Decrypt a credential with systemd-creds. The encrypted credential is
+/// passed on stdin.
+fn decrypt(name: &str, encrypted: &[u8]) -> Result<String> {
+    let run_error = |e| Error::Command(format!("failed to run systemd-creds: {e}"));
+    let mut child = Command::new("systemd-creds")
+        .arg("decrypt")
+        .arg(format!("--name={name}"))
+        .arg("-")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(run_error)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(encrypted).map_err(run_error)?;
+    }
+    let res = child.wait_with_output().map_err(run_error)?;
+    if !res.status.success() {
+        return Err(Error::Credentials(format!(
+            "Could not decrypt credential {name}: {}",
+            String::from_utf8_lossy(&res.stderr).trim()
+        )));
+    }
+    String::from_utf8(res.stdout)
+        .map_err(|e| Error::Credentials(format!("Invalid credential: {name}: {e}")))
+}
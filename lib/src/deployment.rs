@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::path::{Path, PathBuf};
+
+use cap_std::fs::Dir;
+use log::debug;
+use os_release::OsRelease;
+
+use super::bootc::Bootc;
+use super::error::{self, Result};
+use super::ostree::RpmOstree;
+
+/// A tool managing the bootable deployments of the system (rpm-ostree, bootc)
+pub trait Backend: Send + Sync {
+    /// Name of the backend, for logging purposes
+    fn name(&self) -> &'static str;
+
+    /// List all the deployments currently on disk
+    fn deployments(&self) -> Result<Vec<Deployment>>;
+}
+
+/// A single bootable deployment, independent of the backend that manages it
+#[derive(Debug, Clone)]
+pub struct Deployment {
+    pub booted: bool,
+    pub staged: bool,
+    pub rollback: bool,
+    pub pinned: bool,
+    /// Container image reference the deployment has been created from, if any
+    pub image: Option<String>,
+    pub version: Option<String>,
+    /// Path to the deployment checkout, relative to the root directory
+    pub path: PathBuf,
+}
+
+impl Deployment {
+    /// Read the VERSION_ID from the os-release file in the deployment.
+    /// `rootdir` is only used in messages.
+    pub fn version_id(&self, root: &Dir, rootdir: &Path) -> Result<String> {
+        let os_release = self.path.join("usr/lib/os-release");
+        let release: OsRelease = root
+            .read_to_string(&os_release)
+            .map_err(error::io(&rootdir.join(&os_release)))?
+            .lines()
+            .map(String::from)
+            .collect();
+        Ok(release.version_id)
+    }
+}
+
+/// Find which backend manages the deployments for the system. Only available
+/// for the currently booted system as we call out to the tools directly.
+/// `rootdir` is the path of the root directory.
+pub fn detect(root: &Dir, rootdir: &Path) -> Option<Box<dyn Backend>> {
+    if rootdir.as_os_str() != "/" || !root.exists("run/ostree-booted") {
+        debug!("Not on a booted ostree system: no deployment backend");
+        return None;
+    }
+    let backend: Box<dyn Backend> = if root.exists("usr/bin/rpm-ostree") {
+        Box::new(RpmOstree {})
+    } else if root.exists("usr/bin/bootc") {
+        Box::new(Bootc {})
+    } else {
+        debug!("Could not find rpm-ostree or bootc: no deployment backend");
+        return None;
+    };
+    debug!("Using deployment backend: {}", backend.name());
+    Some(backend)
+}
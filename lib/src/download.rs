@@ -0,0 +1,300 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, HeaderMap, HeaderValue, RANGE, RETRY_AFTER};
+use reqwest::{StatusCode, Url};
+
+use super::error::{self, Error, Result};
+use super::progress::{ProgressWriter, Transfer};
+use super::sha256writer::Sha256Writer;
+
+/// Do not wait longer than this between two attempts, even if the server
+/// asks us to
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// How failed requests are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How many times a failed request is retried
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each retry up to
+    /// MAX_RETRY_AFTER
+    pub delay: Duration,
+}
+
+/// Parse a Retry-After header value, either a delay in seconds or a date
+fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Turn HTTP error statuses into errors, with the delay requested by the
+/// server for the ones that are worth retrying
+pub(crate) fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(response);
+    }
+    Err(Error::Http {
+        url: response.url().to_string(),
+        status,
+        retry_after: response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(parse_retry_after),
+    })
+}
+
+/// Whether an error is worth retrying: network errors, rate limiting (HTTP
+/// 429) and server errors (HTTP 5xx)
+fn is_transient(e: &Error) -> bool {
+    match e {
+        Error::Http { status, .. } => {
+            *status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+        }
+        Error::Network(e) => e.status().is_none() && !e.is_builder() && !e.is_redirect(),
+        _ => false,
+    }
+}
+
+/// Call `f` until it succeeds, the error is not transient or we run out of
+/// retries, waiting with an exponential backoff between attempts. The delay
+/// requested by the server via the Retry-After header takes precedence.
+pub fn with_retries<T>(
+    policy: &RetryPolicy,
+    url: &str,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut delay = policy.delay;
+    let mut attempt = 0;
+    loop {
+        let e = match f() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        attempt += 1;
+        if attempt > policy.retries || !is_transient(&e) {
+            return Err(e);
+        }
+        let wait = match &e {
+            Error::Http {
+                retry_after: Some(d),
+                ..
+            } => (*d).min(MAX_RETRY_AFTER),
+            _ => delay.min(MAX_RETRY_AFTER),
+        };
+        warn!(
+            "Attempt {attempt}/{} failed for {url}: {e}. Retrying in {}s",
+            policy.retries + 1,
+            wait.as_secs()
+        );
+        sleep(wait);
+        delay = delay.saturating_mul(2).min(MAX_RETRY_AFTER);
+    }
+}
+
+/// Local path for `file://` URLs and bare absolute paths, used for local
+/// repositories (USB sticks, air-gapped hosts, etc.)
+pub fn local_path(url: &str) -> Result<Option<PathBuf>> {
+    if url.starts_with('/') {
+        return Ok(Some(PathBuf::from(url)));
+    }
+    if !url.starts_with("file:") {
+        return Ok(None);
+    }
+    let path = Url::parse(url)
+        .ok()
+        .and_then(|u| u.to_file_path().ok())
+        .ok_or_else(|| Error::Config(format!("Invalid file URL: {url}")))?;
+    Ok(Some(path))
+}
+
+/// Fetch a small text file, such as a SHA256SUMS file
+pub fn get_text(client: &Client, url: &str, policy: &RetryPolicy) -> Result<String> {
+    if let Some(path) = local_path(url)? {
+        return fs::read_to_string(&path).map_err(error::io(&path));
+    }
+    with_retries(policy, url, || {
+        Ok(check_status(client.get(url).send()?)?.text()?)
+    })
+}
+
+/// Fetch a small binary file, such as a signature
+pub fn get_bytes(client: &Client, url: &str, policy: &RetryPolicy) -> Result<Vec<u8>> {
+    if let Some(path) = local_path(url)? {
+        return fs::read(&path).map_err(error::io(&path));
+    }
+    with_retries(policy, url, || {
+        Ok(check_status(client.get(url).send()?)?.bytes()?.to_vec())
+    })
+}
+
+/// Size of a file, from the Content-Length of a HEAD request for remote
+/// files. Returns None if the server does not tell.
+pub fn size(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    policy: &RetryPolicy,
+) -> Result<Option<u64>> {
+    if let Some(path) = local_path(url)? {
+        return Ok(Some(fs::metadata(&path).map_err(error::io(&path))?.len()));
+    }
+    with_retries(policy, url, || {
+        let response = check_status(client.head(url).headers(headers.clone()).send()?)?;
+        Ok(response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()))
+    })
+}
+
+/// Download a file to the given file and return its sha256sum, retrying on
+/// transient errors. Each retry resumes the download where it stopped. The
+/// headers are sent with each request, for example for authentication. The
+/// file must be readable to resume partial downloads.
+pub fn download(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    file: &mut File,
+    policy: &RetryPolicy,
+    transfer: &mut dyn Transfer,
+) -> Result<String> {
+    if let Some(src) = local_path(url)? {
+        return copy(&src, file, transfer);
+    }
+    with_retries(policy, url, || {
+        download_once(client, url, headers, file, transfer)
+    })
+}
+
+/// Copy a file from a local repository to the given file and return its
+/// sha256sum
+fn copy(src: &Path, file: &mut File, transfer: &mut dyn Transfer) -> Result<String> {
+    let mut src = File::open(src).map_err(error::io(src))?;
+    transfer.start(0, Some(src.metadata()?.len()))?;
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut writer = Sha256Writer::new(ProgressWriter::new(file, transfer));
+    io::copy(&mut src, &mut writer)?;
+    Ok(writer.digest())
+}
+
+/// Download a file to the given file and return its sha256sum. If the file
+/// is not empty, we assume that it is a partial download and try to resume
+/// it using an HTTP Range request. If the server does not support Range
+/// requests, we fall back to a full download.
+fn download_once(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    file: &mut File,
+    transfer: &mut dyn Transfer,
+) -> Result<String> {
+    let offset = file.metadata()?.len();
+    if offset > 0 {
+        if let Some(digest) = resume(client, url, headers, file, offset, transfer)? {
+            return Ok(digest);
+        }
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut response = check_status(client.get(url).headers(headers.clone()).send()?)?;
+    transfer.start(0, response.content_length())?;
+    let mut writer = Sha256Writer::new(ProgressWriter::new(file, transfer));
+    response.copy_to(&mut writer)?;
+    Ok(writer.digest())
+}
+
+/// Try to resume a partial download. Returns None if we have to restart the
+/// download from scratch.
+fn resume(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    file: &mut File,
+    offset: u64,
+    transfer: &mut dyn Transfer,
+) -> Result<Option<String>> {
+    info!("Resuming download at {offset} bytes: {url}");
+
+    let mut response = client
+        .get(url)
+        .headers(headers.clone())
+        .header(RANGE, format!("bytes={offset}-"))
+        .send()?;
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let range = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("");
+            if !range.starts_with(&format!("bytes {offset}-")) {
+                debug!("Unexpected Content-Range: '{range}'. Restarting download");
+                return Ok(None);
+            }
+            transfer.start(offset, response.content_length().map(|l| l + offset))?;
+            // Hash the content that we already have, which leaves us at the
+            // end of the file
+            file.seek(SeekFrom::Start(0))?;
+            let mut writer = Sha256Writer::new(ProgressWriter::new(&*file, transfer));
+            writer.seed(&mut (&*file).take(offset))?;
+            response.copy_to(&mut writer)?;
+            Ok(Some(writer.digest()))
+        }
+        StatusCode::OK => {
+            debug!("Server does not support Range requests. Restarting download");
+            // Re-use the response as it has the full content
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            transfer.start(0, response.content_length())?;
+            let mut writer = Sha256Writer::new(ProgressWriter::new(file, transfer));
+            response.copy_to(&mut writer)?;
+            Ok(Some(writer.digest()))
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            // The server tells us the full length of the file: if it matches
+            // what we have, the previous download completed and we only have
+            // to hash it
+            let length = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|r| r.strip_prefix("bytes */"))
+                .and_then(|l| l.trim().parse::<u64>().ok());
+            if length != Some(offset) {
+                debug!("Invalid range for partial download. Restarting download");
+                return Ok(None);
+            }
+            debug!("Partial download is already complete: {url}");
+            transfer.start(offset, Some(offset))?;
+            file.seek(SeekFrom::Start(0))?;
+            let mut writer = Sha256Writer::new(io::sink());
+            writer.seed(&mut (&*file).take(offset))?;
+            Ok(Some(writer.digest()))
+        }
+        s => {
+            check_status(response)?;
+            Err(Error::Remote(format!("Failed to download {url}: {s}")))
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+//! Errors returned by the library. Variants are stable so that callers can
+//! tell failures apart: new variants may be added but existing ones are not
+//! renamed or removed.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+use super::config::ExtensionType;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Invalid or unreadable configuration file or setting
+    #[error("{0}")]
+    Config(String),
+    /// No configuration for this sysext
+    #[error("No config found for: {0}")]
+    NoConfig(String),
+    /// Image file name that does not follow the naming convention
+    #[error("{0}")]
+    InvalidImage(String),
+    /// No image matching the architecture, the release and the Kind policy
+    #[error("{0}")]
+    NoImage(String),
+    /// Image content does not match the published hash
+    #[error("Invalid hash for {image}: got {actual} vs expected {expected}")]
+    HashMismatch {
+        image: String,
+        expected: String,
+        actual: String,
+    },
+    /// OpenPGP signature verification failed
+    #[error("{0}")]
+    Signature(String),
+    /// The sysext is enabled and the operation would break it
+    #[error("Not removing currently enabled {ext_type}: {name}")]
+    Enabled {
+        name: String,
+        ext_type: ExtensionType,
+    },
+    /// Something is in the way: a file that we do not manage, an existing
+    /// configuration or a concurrent download
+    #[error("{0}")]
+    Conflict(String),
+    /// Another instance holds the global lock
+    #[error("{0}")]
+    Locked(String),
+    /// Not enough free space on the filesystem or store quota exceeded
+    #[error("{0}")]
+    NoSpace(String),
+    /// Server replied with an error status. Server errors and rate limits
+    /// are retried.
+    #[error("HTTP error {status} for {url}")]
+    Http {
+        url: String,
+        status: StatusCode,
+        /// Delay requested by the server with Retry-After
+        retry_after: Option<Duration>,
+    },
+    /// Connection failure, timeout or invalid response
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    /// Unexpected content from a server or a registry
+    #[error("{0}")]
+    Remote(String),
+    /// Missing or invalid credentials
+    #[error("{0}")]
+    Credentials(String),
+    /// Failure of an external command (gpg, systemd-creds, rpm-ostree,
+    /// bootc)
+    #[error("{0}")]
+    Command(String),
+    /// Unsupported architecture or unknown release for the system or one of
+    /// its deployments
+    #[error("{0}")]
+    System(String),
+    /// Invalid or unsupported plan, or a plan change that we refuse to apply
+    #[error("{0}")]
+    Plan(String),
+    /// Filesystem error
+    #[error("{}{source}", .path.as_ref().map(|p| format!("{}: ", p.display())).unwrap_or_default())]
+    Io {
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<rustix::io::Errno> for Error {
+    fn from(errno: rustix::io::Errno) -> Self {
+        io::Error::from(errno).into()
+    }
+}
+
+/// Attach the path to a filesystem error, for use with map_err
+pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| Error::Io {
+        path: Some(path.to_path_buf()),
+        source,
+    }
+}
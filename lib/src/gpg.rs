@@ -0,0 +1,52 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::fs;
+use std::process::Command;
+
+use log::debug;
+use tempfile::TempDir;
+
+use super::error::{Error, Result};
+
+/// Verify a detached OpenPGP signature for some data, only trusting the keys
+/// from the given keyring content. This calls gpg the same way
+/// systemd-sysupdate does.
+pub fn verify(keyring: &[u8], data: &[u8], signature: &[u8]) -> Result<()> {
+    // Use a temporary home directory to not depend on any existing state
+    let home = TempDir::new()?;
+    let keyring_path = home.path().join("keyring.gpg");
+    let data_path = home.path().join("data");
+    let signature_path = home.path().join("data.sig");
+    fs::write(&keyring_path, keyring)?;
+    fs::write(&data_path, data)?;
+    fs::write(&signature_path, signature)?;
+
+    let res = Command::new("gpg")
+        .args([
+            "--no-options",
+            "--no-default-keyring",
+            "--no-auto-key-locate",
+            "--no-auto-check-trustdb",
+            "--batch",
+            "--trust-model=always",
+        ])
+        .arg("--homedir")
+        .arg(home.path())
+        .arg("--keyring")
+        .arg(&keyring_path)
+        .arg("--verify")
+        .arg(&signature_path)
+        .arg(&data_path)
+        .output()
+        .map_err(|e| Error::Command(format!("failed to run gpg: {e}")))?;
+
+    if !res.status.success() {
+        return Err(Error::Signature(format!(
+            "signature verification failed: {}",
+            String::from_utf8_lossy(&res.stderr).trim()
+        )));
+    }
+    debug!("{}", String::from_utf8_lossy(&res.stderr).trim());
+    Ok(())
+}
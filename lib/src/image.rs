@@ -3,10 +3,10 @@
 
 use std::ffi::OsString;
 
-use anyhow::{Result, anyhow};
 use version_compare::Version;
 
 use super::arch::Architecture;
+use super::error::{Error, Result};
 
 #[derive(Debug, Clone)]
 pub struct Image {
@@ -20,22 +20,25 @@ pub struct Image {
 impl Image {
     pub fn new(name: &str, f: OsString, h: Option<String>) -> Result<Image> {
         let Some(filename) = f.to_str() else {
-            return Err(anyhow!("Failed to parse sysext image name: {:?}", name));
+            return Err(Error::InvalidImage(format!(
+                "Failed to parse sysext image name: {:?}",
+                name
+            )));
         };
         let mut filename = filename.to_string();
 
         if !filename.starts_with(name) {
-            return Err(anyhow!(
+            return Err(Error::InvalidImage(format!(
                 "sysext image name must start with its own name: {}",
                 filename
-            ));
+            )));
         }
 
         if !filename.ends_with(".raw") {
-            return Err(anyhow!(
+            return Err(Error::InvalidImage(format!(
                 "sysext image name must end with the `.raw` extension: {}",
                 filename
-            ));
+            )));
         }
         for _ in 0..".raw".len() {
             filename.pop();
@@ -52,18 +55,18 @@ impl Image {
             }
             arch = Architecture::aarch64;
         } else {
-            return Err(anyhow!(
+            return Err(Error::InvalidImage(format!(
                 "sysext image name must be either for x86-64 or arm64: {}",
                 filename
-            ));
+            )));
         }
 
         let mut split: Vec<&str> = filename.split("-").collect();
         if split.len() < 3 {
-            return Err(anyhow!(
+            return Err(Error::InvalidImage(format!(
                 "sysext image name must have a name, version and version id: {}",
                 filename
-            ));
+            )));
         }
 
         let version_id = split.pop().unwrap();
@@ -76,11 +79,10 @@ impl Image {
         version.remove(0);
 
         if Version::from(&version).is_none() {
-            return Err(anyhow!(
+            return Err(Error::InvalidImage(format!(
                 "could not parse version '{}': {}",
-                version,
-                filename
-            ));
+                version, filename
+            )));
         }
 
         Ok(Image {
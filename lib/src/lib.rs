@@ -2,8 +2,20 @@
 // SPDX-License-Identifier: MIT
 
 pub mod arch;
+pub mod bootc;
 pub mod config;
+pub mod credentials;
+pub mod deployment;
+pub mod download;
+pub mod error;
+pub mod gpg;
 pub mod image;
+pub mod lock;
 pub mod manager;
+pub mod oci;
 pub mod ostree;
+pub mod plan;
+pub mod progress;
+pub mod report;
+pub mod reporter;
 pub mod sha256writer;
@@ -0,0 +1,147 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use cap_std::fs::{Dir, DirBuilder, DirBuilderExt, MetadataExt as _, OpenOptions, OpenOptionsExt};
+use log::{debug, info};
+use rustix::fs::{FlockOperation, flock};
+use rustix::io::Errno;
+
+use super::error::{self, Error, Result};
+
+/// Directory for the lock file, relative to the root directory
+pub const LOCK_DIR: &str = "run/sysexts-manager";
+pub const LOCK_FILE: &str = "lock";
+
+/// Delay between two attempts to take the lock when waiting for it
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to do when another process holds the lock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Wait {
+    /// Fail right away
+    #[default]
+    No,
+    /// Wait for at most the given duration
+    Timeout(Duration),
+    /// Wait until the lock is released
+    Forever,
+}
+
+/// Advisory lock serializing processes that modify the configuration, the
+/// images or the enabled extensions. The lock file holds the PID of the
+/// process holding the lock and is removed when the lock is dropped.
+#[derive(Debug)]
+pub struct Lock {
+    dir: Dir,
+    path: PathBuf,
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock in the given root directory. `rootdir` is only used in
+    /// messages.
+    pub fn acquire_in(root: &Dir, rootdir: &Path, wait: Wait) -> Result<Lock> {
+        let path = rootdir.join(LOCK_DIR).join(LOCK_FILE);
+        root.create_dir_with(LOCK_DIR, DirBuilder::new().recursive(true).mode(0o755))
+            .map_err(error::io(&rootdir.join(LOCK_DIR)))?;
+        let dir = root
+            .open_dir(LOCK_DIR)
+            .map_err(error::io(&rootdir.join(LOCK_DIR)))?;
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let mut file = dir
+                .open_with(
+                    LOCK_FILE,
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(false)
+                        .mode(0o644),
+                )
+                .map_err(error::io(&path))?
+                .into_std();
+            match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+                Ok(()) => {
+                    // The previous holder removes the file when releasing the
+                    // lock, possibly after we opened it
+                    if !is_same_file(&file, &dir) {
+                        debug!("Lock file was removed, trying again");
+                        continue;
+                    }
+                    file.set_len(0)?;
+                    writeln!(file, "{}", std::process::id())?;
+                    debug!("Took lock: {}", path.display());
+                    return Ok(Lock {
+                        dir,
+                        path,
+                        _file: file,
+                    });
+                }
+                Err(Errno::WOULDBLOCK) => {}
+                Err(e) => {
+                    return Err(error::io(&path)(e.into()));
+                }
+            }
+            let holder = holder(&mut file);
+            match wait {
+                Wait::No => {
+                    return Err(Error::Locked(format!(
+                        "Another sysexts-manager instance ({holder}) is running (lock: {})",
+                        path.display()
+                    )));
+                }
+                Wait::Timeout(t) if start.elapsed() >= t => {
+                    return Err(Error::Locked(format!(
+                        "Timed out after {}s waiting for another sysexts-manager instance ({holder}) (lock: {})",
+                        t.as_secs(),
+                        path.display()
+                    )));
+                }
+                _ => {}
+            }
+            if !waiting {
+                info!("Waiting for another sysexts-manager instance ({holder})");
+                waiting = true;
+            }
+            sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Remove the file while still holding the lock, the lock is released
+        // when the file is closed
+        if let Err(e) = self.dir.remove_file(LOCK_FILE) {
+            debug!("Could not remove lock file {}: {e}", self.path.display());
+        }
+    }
+}
+
+fn is_same_file(file: &File, dir: &Dir) -> bool {
+    match (file.metadata(), dir.metadata(LOCK_FILE)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Describe the process holding the lock from the PID in the lock file
+fn holder(file: &mut File) -> String {
+    let mut content = String::new();
+    if file.rewind().is_err() || file.read_to_string(&mut content).is_err() {
+        return "unknown PID".to_string();
+    }
+    match content.trim().parse::<u32>() {
+        Ok(pid) => format!("PID {pid}"),
+        Err(_) => "unknown PID".to_string(),
+    }
+}
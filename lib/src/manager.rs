@@ -1,30 +1,69 @@
 // SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
 // SPDX-License-Identifier: MIT
 
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::env::consts::ARCH;
-use std::fs::{self, File, remove_file, rename, symlink_metadata};
-use std::io::Write;
-use std::os::unix::fs::symlink;
+use std::error::Error as _;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{MetadataExt as _, PermissionsExt, fchown};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use anyhow::{Context, Result, anyhow};
+use base64::prelude::*;
+use cap_std::ambient_authority;
+use cap_std::fs::{Dir, DirBuilder, DirBuilderExt, MetadataExt, OpenOptions, OpenOptionsExt};
 use log::{debug, error, info, warn};
 use os_release::OsRelease;
-// use cap_std::fs::Dir;
 use rayon::prelude::*;
+use reqwest::blocking::Client;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use reqwest::{Certificate, Identity, NoProxy, Proxy};
+use rustix::fs::{FlockOperation, flock, fstatvfs};
 use version_compare::{Cmp, compare};
 
 use super::arch::Architecture;
-use super::config::Config;
+use super::config::{AuthType, Config, ExtensionType, GlobalConfig};
+use super::credentials;
+use super::deployment::{self, Backend, Deployment};
+use super::download;
+use super::error::{self, Error, Result};
+use super::gpg;
 use super::image::Image;
-use super::sha256writer::Sha256Writer;
+use super::lock::{self, Lock, Wait};
+use super::oci;
+use super::plan::{Change, Plan};
+use super::progress::Transfer;
+use super::report::{
+    Added, Cleaned, ConfigError, Disabled, Enabled, ImageStatus, Removed, Status, SysextStatus,
+    UpdateStatus, Updated,
+};
+use super::reporter::{Event, NoReporter, Reporter};
+use super::sha256writer::sha256sum;
 
 pub struct Manager {
     system: System,
+    global: GlobalConfig,
     configs: HashMap<String, Config>,
+    /// Configuration file for each sysext
+    sources: HashMap<String, PathBuf>,
+    /// Configuration files that could not be loaded
+    config_errors: Vec<ConfigError>,
     images: HashMap<String, Vec<Image>>,
+    /// Root directory of the target system. The configuration, the store and
+    /// the extensions directories are only accessed through it so that
+    /// symlinks can not point outside of it.
+    root: Dir,
+    /// Path to the root directory, for messages
     rootdir: PathBuf,
+    backend: Option<Box<dyn Backend>>,
+    reporter: Box<dyn Reporter>,
+    /// Serialize free space checks and pruning between parallel downloads
+    space: Mutex<()>,
+    /// What to do when another instance holds the lock
+    wait: Wait,
 }
 
 struct System {
@@ -40,15 +79,18 @@ const ALL_CONFIG_DIRS: &[&str] = &[
     "usr/lib/sysexts-manager",
 ];
 
-const RUNTIME_EXTENSIONS_DIR: &str = "run/extensions";
-// const PERMANENT_EXTENSIONS_DIR: &str = "var/lib/extensions";
-const ALL_EXTENSIONS_DIRS: &[&str] = &["run/extensions", "etc/extensions", "var/lib/extensions"];
+const ALL_GLOBAL_CONFIG_FILES: &[&str] = &[
+    "run/sysexts-manager.conf",
+    "etc/sysexts-manager.conf",
+    "usr/lib/sysexts-manager.conf",
+];
 
 const DEFAULT_STORE: &str = "var/lib/extensions.d";
 
+/// Mode for the images in the store
+const IMAGE_MODE: u32 = 0o644;
+
 pub fn new() -> Result<Manager> {
-    // let dir = cap_std::open_ambient_dir("/")?;
-    // new_with_root(Path::new(dir))
     new_with_root(Path::new("/"))
 }
 
@@ -63,29 +105,71 @@ pub fn new_with_root(path: &Path) -> Result<Manager> {
         "x86_64" => Architecture::x86_64,
         "aarch64" => Architecture::aarch64,
         a => {
-            return Err(anyhow!("Architecture is not supported: {a}"));
+            return Err(Error::System(format!("Architecture is not supported: {a}")));
         }
     };
 
-    let release = OsRelease::new_from(path.join("etc/os-release"))?;
+    let root = Dir::open_ambient_dir(path, ambient_authority()).map_err(error::io(path))?;
+    let release: OsRelease = root
+        .read_to_string("etc/os-release")
+        .map_err(error::io(&path.join("etc/os-release")))?
+        .lines()
+        .map(String::from)
+        .collect();
     let version_id = release.version_id;
+    let backend = deployment::detect(&root, path);
 
     debug!("Found arch: {arch} | version_id: {version_id}");
 
     Ok(Manager {
         system: System { arch, version_id },
+        global: GlobalConfig::default(),
         configs: HashMap::new(),
+        sources: HashMap::new(),
+        config_errors: vec![],
         images: HashMap::new(),
+        root,
         rootdir: path.into(),
+        backend,
+        reporter: Box::new(NoReporter),
+        space: Mutex::new(()),
+        wait: Wait::default(),
     })
 }
 
 impl Manager {
+    /// Send events to this implementation. Events are ignored by default.
+    pub fn set_reporter(&mut self, reporter: Box<dyn Reporter>) {
+        self.reporter = reporter;
+    }
+
+    /// Wait for other instances to release the lock instead of failing right
+    /// away
+    pub fn set_wait(&mut self, wait: Wait) {
+        self.wait = wait;
+    }
+
+    /// Take the global lock. Taken by all methods that modify the
+    /// configuration, the images or the enabled extensions.
+    fn lock(&self) -> Result<Lock> {
+        Lock::acquire_in(&self.root, &self.rootdir, self.wait)
+    }
+
     pub fn load_config(&mut self) -> Result<()> {
+        for file in ALL_GLOBAL_CONFIG_FILES {
+            if self.root.exists(file) {
+                let path = self.rootdir.join(file);
+                debug!("Loading global configuration from: {}", path.display());
+                self.global = GlobalConfig::parse(&self.read_config(Path::new(file))?, &path)?;
+                break;
+            }
+        }
+        debug!("Global configuration: {:?}", self.global);
+
         for dir in ALL_CONFIG_DIRS {
             let configdir = self.rootdir.join(dir);
             debug!("Looking for configuration in: {}", configdir.display());
-            let Ok(files) = fs::read_dir(&configdir) else {
+            let Ok(files) = self.root.read_dir(dir) else {
                 debug!(
                     "Could not find configuration directory: {}",
                     configdir.display()
@@ -97,17 +181,34 @@ impl Manager {
                     error!("Could not get filename from direntry");
                     continue;
                 };
-                let Ok(config) = Config::new(filename.path().as_path()) else {
-                    error!(
-                        "Error reading configuration file: {}",
-                        filename.path().display()
-                    );
+                let path = Path::new(dir).join(filename.file_name());
+                let full_path = self.rootdir.join(&path);
+                if self.root.is_dir(&path) {
+                    debug!("Skipping directory: {}", full_path.display());
                     continue;
+                }
+                if *dir == lock::LOCK_DIR && filename.file_name() == lock::LOCK_FILE {
+                    continue;
+                }
+                let config = match self
+                    .read_config(&path)
+                    .and_then(|c| Config::parse(&c, &full_path))
+                {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("Error reading configuration file: {}", full_path.display());
+                        self.config_errors.push(ConfigError {
+                            source: system_path(&path),
+                            error: format!("{e:#}"),
+                        });
+                        continue;
+                    }
                 };
                 debug!("Valid configuration file for sysext: {:?}", &config);
                 if self.configs.contains_key(&config.Name) {
-                    info!("Ignoring config file: {}", filename.path().display())
+                    info!("Ignoring config file: {}", full_path.display())
                 } else {
+                    self.sources.insert(config.Name.clone(), system_path(&path));
                     self.configs.insert(config.Name.clone(), config);
                 }
             }
@@ -122,59 +223,69 @@ impl Manager {
         Ok(())
     }
 
-    pub fn load_images(&mut self) -> Result<()> {
+    /// Read a configuration file, relative to the root directory
+    fn read_config(&self, path: &Path) -> Result<String> {
+        self.root.read_to_string(path).map_err(|_| {
+            Error::Config(format!(
+                "Could not read content from file: {}",
+                self.rootdir.join(path).display()
+            ))
+        })
+    }
+
+    /// Find the images in the store for the configured sysexts
+    fn scan_images(&self) -> HashMap<String, Vec<Image>> {
+        let mut images: HashMap<String, Vec<Image>> = HashMap::new();
         let sysext_store = self.rootdir.join(DEFAULT_STORE);
         debug!("Looking for sysext images in: {}", sysext_store.display());
-        let Ok(files) = fs::read_dir(&sysext_store) else {
+        let Ok(files) = self.root.read_dir(DEFAULT_STORE) else {
             debug!(
                 "Could not find sysext directory: {}",
                 sysext_store.display()
             );
-            return Ok(());
+            return images;
         };
         for file in files {
             let Ok(filename) = file else {
                 error!("Could not get filename from direntry");
                 continue;
             };
-            debug!("Looking at sysext image: {}", filename.path().display());
+            let path = sysext_store.join(filename.file_name());
+            debug!("Looking at sysext image: {}", path.display());
+            let filename_osstr = filename.file_name();
+            let filename_str = filename_osstr.to_string_lossy();
+            if filename_str.ends_with(".tmp") {
+                // Kept to resume the download on next update
+                debug!("Skipping partial download: {filename_str}");
+                continue;
+            }
             let mut found = false;
             for name in self.configs.keys() {
-                let filename_osstr = filename.file_name();
-                let filename_str = filename_osstr.to_str().unwrap();
-                if filename_str.ends_with(".tmp") {
-                    debug!("Cleaning up temporary file: {filename_str}");
-                    remove_file(self.rootdir.join(DEFAULT_STORE).join(filename_osstr))?;
-                    continue;
-                }
                 if filename_str.starts_with(name) {
                     found = true;
                     let Ok(image) = Image::new(name, filename.file_name(), None) else {
-                        error!("Invalid sysext name: {}", filename.path().display());
+                        error!("Invalid sysext name: {}", path.display());
                         break;
                     };
-                    match self.images.get_mut(&image.name) {
-                        None => {
-                            debug!("Adding sysext image to new list: {image:?}");
-                            let name = image.name.clone();
-                            let vec = vec![image];
-                            self.images.insert(name, vec);
-                        }
-                        Some(v) => {
-                            debug!("Adding sysext image to existing list: {image:?}");
-                            v.push(image);
-                        }
-                    }
+                    debug!("Adding sysext image: {image:?}");
+                    images.entry(image.name.clone()).or_default().push(image);
                     break;
                 }
             }
             if !found {
                 error!(
                     "Could not find a matching config for sysext image: {}. Ignoring",
-                    filename.path().display()
+                    path.display()
                 );
             }
         }
+        images
+    }
+
+    pub fn load_images(&mut self) -> Result<()> {
+        for (name, images) in self.scan_images() {
+            self.images.entry(name).or_default().extend(images);
+        }
         if self.images.is_empty() {
             info!("No sysext loaded");
         } else {
@@ -200,101 +311,213 @@ impl Manager {
         Ok(())
     }
 
-    pub fn enable_all(&self) -> Result<()> {
-        let run_extensions = self.rootdir.join(RUNTIME_EXTENSIONS_DIR);
-        if !run_extensions.exists() {
+    /// Create the runtime directory for an extension type if needed
+    fn create_runtime_dir(&self, ext_type: ExtensionType) -> Result<()> {
+        let dir = ext_type.runtime_dir();
+        let run_extensions = self.rootdir.join(dir);
+        if !self.root.exists(dir) {
             debug!("Creating {}", &run_extensions.display());
-            fs::create_dir(&run_extensions)?;
+            self.root.create_dir(dir)?;
         }
-        if !fs::metadata(&run_extensions)?.is_dir() {
-            return Err(anyhow!("{} is not a directory", &run_extensions.display()));
+        if !self.root.metadata(dir)?.is_dir() {
+            return Err(Error::Conflict(format!(
+                "{} is not a directory",
+                &run_extensions.display()
+            )));
         }
+        Ok(())
+    }
+
+    pub fn enable_all(&self) -> Result<Vec<Enabled>> {
+        let _lock = self.lock()?;
+        let mut enabled = vec![];
         for name in self.configs.keys() {
-            self.enable_one(name, &run_extensions)?;
+            enabled.push(self.enable_one(name)?);
         }
-        Ok(())
+        Ok(enabled)
     }
 
-    pub fn enable(&self, name: &String) -> Result<()> {
-        let run_extensions = self.rootdir.join(RUNTIME_EXTENSIONS_DIR);
-        if !run_extensions.exists() {
-            debug!("Creating {}", &run_extensions.display());
-            fs::create_dir(&run_extensions)?;
+    pub fn enable(&self, name: &String) -> Result<Enabled> {
+        let _lock = self.lock()?;
+        self.enable_one(name)
+    }
+
+    /// Changes needed to enable all sysexts, or only one if specified
+    pub fn plan_enable(&self, name: Option<&String>) -> Result<Plan> {
+        let names: Vec<&String> = match name {
+            None => self.configs.keys().collect(),
+            Some(n) => vec![n],
+        };
+        let mut changes = vec![];
+        for name in names {
+            changes.extend(self.enable_change(name)?.1);
         }
-        if !fs::metadata(&run_extensions)?.is_dir() {
-            return Err(anyhow!("{} is not a directory", &run_extensions.display()));
+        Ok(Plan::new(changes))
+    }
+
+    fn enable_one(&self, name: &String) -> Result<Enabled> {
+        let (image, change) = self.enable_change(name)?;
+        if let Some(change) = change {
+            self.apply_change(&change)?;
         }
-        self.enable_one(name, &run_extensions)
+        let enabled = Enabled {
+            name: name.clone(),
+            ext_type: self.ext_type(name),
+            image: image.path(),
+        };
+        self.reporter.report(&Event::Enabled(&enabled));
+        Ok(enabled)
     }
 
-    /// Enable a sysext: create a symlink in /run/extensions that points to the
-    /// latest image matching the Kind policy (only latest implemented for now)
-    fn enable_one(&self, name: &String, dir: &Path) -> Result<()> {
+    /// Enable a sysext or confext: create a symlink in /run/extensions (or
+    /// /run/confexts) that points to the image matching the Kind policy
+    /// (latest or pinned version). Returns the image and the change to
+    /// make, if it is not already enabled.
+    fn enable_change(&self, name: &String) -> Result<(Image, Option<Change>)> {
         let config = self
             .configs
             .get(name)
-            .context(format!("No config found for: {name}"))?;
-
-        if config.Kind != "latest" {
-            unimplemented!();
-        }
+            .ok_or_else(|| Error::NoConfig(name.clone()))?;
 
-        let images = self.images.get(name).context(format!(
-            "Found config but no images for: {name}. Not setting up. Update first."
-        ))?;
+        let images = self.images.get(name).ok_or_else(|| {
+            Error::NoImage(format!(
+                "Found config but no images for: {name}. Not setting up. Update first."
+            ))
+        })?;
 
         let image = self
-            .find_latest_image(name, images)?
-            .context(format!("No image to enable for sysext: {name}"))?;
+            .find_latest_image(config, images, &self.system.version_id)?
+            .ok_or_else(|| Error::NoImage(format!("No image to enable for sysext: {name}")))?;
 
-        info!("Enabling sysext: {} ({})", image.name, image.version);
+        info!(
+            "Enabling {}: {} ({})",
+            config.Type, image.name, image.version
+        );
 
-        let original = format!("../../var/lib/extensions.d/{}", image.path());
-        let link = dir.join(format!("{name}.raw"));
-        debug!("{} -> {original}", link.display());
-        if symlink_metadata(&link).is_ok() {
-            remove_file(&link)?
+        let target = PathBuf::from(format!("../../var/lib/extensions.d/{}", image.path()));
+        let path = system_path(config.Type.runtime_dir()).join(format!("{name}.raw"));
+        let link = self.root_path(&path);
+        debug!("{} -> {}", link.display(), target.display());
+        let previous = match self.root.symlink_metadata(relative_path(&path)) {
+            Err(_) => None,
+            Ok(m) if m.file_type().is_symlink() => {
+                Some(self.root.read_link_contents(relative_path(&path))?)
+            }
+            Ok(_) => {
+                return Err(Error::Conflict(format!(
+                    "Not overriding an existing file for: {}",
+                    link.display()
+                )));
+            }
         };
-        if link.exists() {
-            return Err(anyhow!(
-                "Not overriding an existing file for: {}",
-                link.display()
-            ));
+        if previous.as_ref() == Some(&target) {
+            debug!("Already enabled: {}", link.display());
+            return Ok((image, None));
+        }
+        let change = Change::Link {
+            name: name.clone(),
+            path,
+            target,
+            previous,
         };
-        symlink(original, link)?;
-        println!("Enabled sysext: {name}");
-        Ok(())
+        Ok((image, Some(change)))
     }
 
-    pub fn disable_all(&self) -> Result<()> {
-        let run_extensions = self.rootdir.join(RUNTIME_EXTENSIONS_DIR);
-        for name in self.configs.keys() {
-            self.disable_one(name, &run_extensions)?;
+    pub fn disable_all(&self) -> Result<Vec<Disabled>> {
+        let _lock = self.lock()?;
+        let mut disabled = vec![];
+        for (name, config) in &self.configs {
+            let run_extensions = Path::new(config.Type.runtime_dir());
+            disabled.extend(self.disable_in(name, run_extensions)?);
         }
-        Ok(())
+        Ok(disabled)
     }
 
-    pub fn disable(&self, name: &String) -> Result<()> {
-        let run_extensions = self.rootdir.join(RUNTIME_EXTENSIONS_DIR);
-        self.disable_one(name, &run_extensions)
+    /// Disable a sysext. Returns None if it was not enabled.
+    pub fn disable(&self, name: &String) -> Result<Option<Disabled>> {
+        let _lock = self.lock()?;
+        let run_extensions = Path::new(self.ext_type(name).runtime_dir());
+        self.disable_in(name, run_extensions)
     }
 
-    pub fn disable_one(&self, name: &String, dir: &Path) -> Result<()> {
-        let sysext = dir.join(format!("{name}.raw"));
-        if !sysext.exists() {
-            debug!("sysext already disabled: {}", &sysext.display());
-            return Ok(());
+    /// Changes needed to disable all sysexts, or only one if specified
+    pub fn plan_disable(&self, name: Option<&String>) -> Result<Plan> {
+        let names: Vec<&String> = match name {
+            None => self.configs.keys().collect(),
+            Some(n) => vec![n],
+        };
+        let mut changes = vec![];
+        for name in names {
+            let run_extensions = Path::new(self.ext_type(name).runtime_dir());
+            changes.extend(self.disable_change(name, run_extensions));
         }
-        remove_file(&sysext)?;
-        println!("Disabled sysext: {name}");
-        Ok(())
+        Ok(Plan::new(changes))
+    }
+
+    /// Type of extension for a name. Defaults to sysext to be able to disable
+    /// images without a config.
+    fn ext_type(&self, name: &str) -> ExtensionType {
+        self.configs.get(name).map(|c| c.Type).unwrap_or_default()
     }
 
+    /// Disable a sysext in a given extensions directory, relative to the
+    /// target root
+    fn disable_in(&self, name: &String, dir: &Path) -> Result<Option<Disabled>> {
+        let Some(change) = self.disable_change(name, dir) else {
+            return Ok(None);
+        };
+        self.apply_change(&change)?;
+        let Change::Unlink { target, .. } = change else {
+            unreachable!();
+        };
+        let disabled = Disabled {
+            name: name.clone(),
+            ext_type: self.ext_type(name),
+            image: target.as_deref().and_then(image_name),
+        };
+        self.reporter.report(&Event::Disabled(&disabled));
+        Ok(Some(disabled))
+    }
+
+    fn disable_change(&self, name: &String, dir: &Path) -> Option<Change> {
+        let sysext = relative_path(dir).join(format!("{name}.raw"));
+        // Absolute symlinks can not be followed within the root directory
+        if self.root.symlink_metadata(&sysext).is_err() {
+            debug!(
+                "{} already disabled: {}",
+                self.ext_type(name),
+                self.rootdir.join(&sysext).display()
+            );
+            return None;
+        }
+        Some(Change::Unlink {
+            name: name.clone(),
+            path: system_path(&sysext),
+            target: self.root.read_link_contents(&sysext).ok(),
+        })
+    }
+
+    /// Find the image to use for a sysext according to its Kind policy: the
+    /// latest one or the one matching the pinned version, for the current
+    /// architecture and the given release.
     fn find_latest_image(
         &self,
-        name: &String,
-        sysext_images: &Vec<Image>,
+        config: &Config,
+        sysext_images: &[Image],
+        version_id: &str,
     ) -> Result<Option<Image>> {
+        let name = &config.Name;
+        let pinned = match config.Kind.as_str() {
+            "latest" => None,
+            "version" => Some(config.Version.as_ref().ok_or_else(|| {
+                Error::Config(format!("No version set for pinned sysext: {name}"))
+            })?),
+            k => {
+                return Err(Error::Config(format!(
+                    "Unsupported Kind for sysext {name}: {k}"
+                )));
+            }
+        };
         let mut latest = None;
         for image in sysext_images {
             // Filter images that we can not use
@@ -302,10 +525,14 @@ impl Manager {
                 info!("Ignoring '{}' (incompatible architecture)", image.path());
                 continue;
             }
-            if image.version_id != self.system.version_id {
+            if image.version_id != version_id {
                 info!("Ignoring '{}' (incompatible release)", image.path());
                 continue;
             }
+            if pinned.is_some_and(|v| &image.version != v) {
+                debug!("Ignoring '{}' (not the pinned version)", image.path());
+                continue;
+            }
             match &latest {
                 None => {
                     latest = Some(image.clone());
@@ -320,154 +547,226 @@ impl Manager {
                         debug!("{}: Selecting {}", name, image.version);
                         latest = Some(image.clone());
                     }
-                    _ => return Err(anyhow!("Invalid version number")),
+                    _ => return Err(Error::InvalidImage("Invalid version number".into())),
                 },
             };
         }
         Ok(latest)
     }
 
-    pub fn add_sysext(&self, name: &str, kind: &str, url: &str, force: &bool) -> Result<()> {
-        debug!("Adding sysext config: {name}, {kind}, {url} (override: {force})");
-
-        let conf = Config {
-            Name: name.into(),
-            Kind: kind.into(),
-            Url: url.into(),
-        };
+    pub fn add_sysext(&mut self, conf: &Config, force: &bool) -> Result<Added> {
+        let _lock = self.lock()?;
+        debug!("Adding config: {conf:?} (override: {force})");
+        conf.validate()?;
+        let name = &conf.Name;
+        let url = &conf.Url;
+        let ext_type = conf.Type;
 
         let configdir = self.rootdir.join(DEFAULT_CONFIG_DIR);
-        if !configdir.exists() {
+        if !self.root.exists(DEFAULT_CONFIG_DIR) {
             debug!("Creating {}", &configdir.display());
-            fs::create_dir(&configdir)?;
+            self.root.create_dir(DEFAULT_CONFIG_DIR)?;
         }
-        if !fs::metadata(&configdir)?.is_dir() {
-            return Err(anyhow!("{} is not a directory", &configdir.display()));
+        if !self.root.metadata(DEFAULT_CONFIG_DIR)?.is_dir() {
+            return Err(Error::Conflict(format!(
+                "{} is not a directory",
+                &configdir.display()
+            )));
         }
 
-        let conffile = configdir.join(format!("{name}.conf"));
-        if conffile.exists() && !*force {
-            return Err(anyhow!(
+        let conffile = Path::new(DEFAULT_CONFIG_DIR).join(format!("{name}.conf"));
+        if self.root.exists(&conffile) && !*force {
+            return Err(Error::Conflict(format!(
                 "{} already exists (use --force to override it)",
-                conffile.display()
-            ));
+                self.rootdir.join(&conffile).display()
+            )));
         }
 
-        let mut file = File::create(conffile)?;
-        file.write_all(&toml::to_string(&conf)?.into_bytes())?;
-
-        // TODO: Add config to manager
+        let content = toml::to_string(conf)
+            .map_err(|e| Error::Config(format!("Could not serialize config for {name}: {e}")))?;
+        self.root.write(&conffile, content)?;
 
-        println!("Added configuration for sysext: {name} ({url})");
+        let source = system_path(&conffile);
+        self.config_errors.retain(|e| e.source != source);
+        // Configuration files in /run take precedence over the ones in /etc
+        let precedence = |path: &Path| {
+            ALL_CONFIG_DIRS
+                .iter()
+                .position(|d| path.parent() == Some(&system_path(d)))
+        };
+        match self.sources.get(name) {
+            Some(s) if precedence(s) < precedence(&source) => {
+                info!(
+                    "Configuration for {name} from {} takes precedence",
+                    s.display()
+                );
+            }
+            _ => {
+                self.sources.insert(name.clone(), source.clone());
+                self.configs.insert(name.clone(), conf.clone());
+            }
+        }
 
-        Ok(())
+        Ok(Added {
+            name: name.clone(),
+            ext_type,
+            url: url.clone(),
+            source,
+        })
     }
 
-    pub fn remove_sysext(&mut self, name: &str) -> Result<()> {
+    /// Remove the configuration and the images for a sysext. Returns None if
+    /// there is no configuration for it.
+    pub fn remove_sysext(&mut self, name: &str) -> Result<Option<Removed>> {
+        let _lock = self.lock()?;
         debug!("Removing sysext config and images: {name}");
 
-        match self.configs.get(name) {
+        let Some(ext_type) = self.configs.get(name).map(|c| c.Type) else {
+            info!("No configuration found for: {name}");
+            return Ok(None);
+        };
+
+        let mut files = vec![];
+        for change in self.plan_remove(name)?.changes {
+            if let Change::Delete { path, .. } = &change {
+                info!("Removing: {}", path.display());
+                files.push(path.clone());
+            }
+            self.apply_change(&change)?;
+        }
+        self.images.remove(name);
+        self.configs.remove(name);
+        self.sources.remove(name);
+
+        Ok(Some(Removed {
+            name: name.to_string(),
+            ext_type,
+            files,
+        }))
+    }
+
+    /// Changes needed to remove the configuration and the images for a
+    /// sysext. Fails if the sysext is currently enabled.
+    pub fn plan_remove(&self, name: &str) -> Result<Plan> {
+        let ext_type = match self.configs.get(name) {
             None => {
                 info!("No configuration found for: {name}");
-                return Ok(());
+                return Ok(Plan::default());
             }
-            Some(_c) => {}
+            Some(c) => c.Type,
         };
 
-        for dir in ALL_EXTENSIONS_DIRS {
-            let symlink = self.rootdir.join(dir).join(format!("{name}.raw"));
-            if symlink.exists() {
-                info!("Found symlink: {}", symlink.display());
-                return Err(anyhow!("Not removing currently enabled sysext: {name}"));
+        for dir in ext_type.extensions_dirs() {
+            let symlink = Path::new(dir).join(format!("{name}.raw"));
+            if self.root.symlink_metadata(&symlink).is_ok() {
+                info!("Found symlink: {}", self.rootdir.join(&symlink).display());
+                return Err(Error::Enabled {
+                    name: name.to_string(),
+                    ext_type,
+                });
             }
         }
 
+        let mut changes = vec![];
         match self.images.get(name) {
             None => {
                 debug!("No images to remove");
             }
             Some(v) => {
-                let images = v
-                    .iter()
-                    .filter(|i| i.name == name)
-                    .map(|i| i.path())
-                    .collect::<Vec<String>>();
-                let sysext_store = self.rootdir.join(DEFAULT_STORE);
-                for image in images {
-                    let path = sysext_store.join(image);
-                    info!("Removing sysext image: {}", path.display());
-                    remove_file(&path)?
+                for image in v.iter().filter(|i| i.name == name) {
+                    let path = system_path(DEFAULT_STORE).join(image.path());
+                    let size = self.root.symlink_metadata(relative_path(&path))?.len();
+                    changes.push(Change::Delete { path, size });
                 }
-                self.images.remove(name);
             }
         };
 
-        // Try removing the config from /run and /etc, ignore /usr
+        // Remove the config from /run and /etc, ignore /usr
         for dir in MUTABLE_CONFIG_DIRS {
-            let conf = self.rootdir.join(dir).join(format!("{name}.conf"));
-            if conf.exists() {
-                info!("Removing sysext config: {}", conf.display());
-                remove_file(&conf)?;
+            let path = system_path(dir).join(format!("{name}.conf"));
+            if let Ok(metadata) = self.root.symlink_metadata(relative_path(&path)) {
+                changes.push(Change::Delete {
+                    path,
+                    size: metadata.len(),
+                });
             }
         }
-        self.configs.remove(name);
-
-        println!("Removed configuration and images for sysext: {name}");
+        Ok(Plan::new(changes))
+    }
 
-        Ok(())
+    fn update_sysext(
+        &self,
+        config: &Config,
+        images: &[Image],
+        version_id: &str,
+        repair: bool,
+    ) -> Updated {
+        let mut updated = Updated {
+            name: config.Name.clone(),
+            version_id: version_id.to_string(),
+            status: UpdateStatus::Updated,
+            image: None,
+            error: None,
+        };
+        let image = match self.find_update(config, images, version_id, repair) {
+            Ok(Update::Download(image)) => image,
+            Ok(Update::Nothing(status)) => {
+                updated.status = status;
+                return updated;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to update sysext: {} (VERSION_ID: {version_id}): {e}",
+                    config.Name
+                );
+                updated.status = UpdateStatus::Failed;
+                updated.error = Some(e);
+                return updated;
+            }
+        };
+        updated.image = Some(image.path());
+        if let Err(e) = self.download_image(config, &image) {
+            error!(
+                "Failed to update sysext: {} (VERSION_ID: {version_id}): {e}",
+                config.Name
+            );
+            updated.status = UpdateStatus::Failed;
+            updated.error = Some(e);
+        }
+        updated
     }
 
-    fn update_sysext(&self, config: &Config, images: &Vec<Image>) -> Result<()> {
+    /// Find the image to download for a sysext and release, if any: a newer
+    /// image than the latest local one or, with `repair`, the same image if
+    /// the local one does not match the published hash.
+    fn find_update(
+        &self,
+        config: &Config,
+        images: &[Image],
+        version_id: &str,
+        repair: bool,
+    ) -> Result<Update> {
         debug!(
-            "Downloading SHA256SUMS for: {} (version_id: {}, arch: {})",
-            config.Name, self.system.version_id, self.system.arch
+            "Looking for updates for: {} (version_id: {}, arch: {})",
+            config.Name, version_id, self.system.arch
         );
-        let sha256sum_url = format!("{}/{}/SHA256SUMS", config.Url, config.Name);
-        debug!("Downloading: {sha256sum_url}");
-        let client = reqwest::blocking::Client::new();
-        let sha256sums = client.get(sha256sum_url).send()?.text()?;
-        debug!("{sha256sums}");
-
-        // Parse images and hashes list from SHA256SUM file
-        let mut remote_images: Vec<Image> = Vec::new();
-        for line in sha256sums.lines() {
-            let mut split = line.split("  ");
-            let hash: String = match split.next() {
-                Some(s) => s.into(),
-                None => {
-                    error!("Invalid line in SHA256SUMS file: {line}");
-                    continue;
-                }
-            };
-            let filename: String = match split.next() {
-                Some(s) => s.into(),
-                None => {
-                    error!("Invalid line in SHA256SUMS file: {line}");
-                    continue;
-                }
-            };
-            let Ok(image) = Image::new(&config.Name, filename.clone().into(), Some(hash)) else {
-                warn!("Ignoring invalid sysext: {filename}");
-                continue;
-            };
-            remote_images.push(image);
-        }
-        let parsed_sha256sums = remote_images
+        let remote_images = self.remote_images(config)?;
+        let found_images = remote_images
             .iter()
             .map(|i| format!("{} ({})", i.path(), i.hash.clone().unwrap_or("?".into())))
             .collect::<Vec<String>>()
             .join("\n");
-        if parsed_sha256sums.is_empty() {
-            warn!("Empty SHA256SUMS file for: {}", config.Name);
-            return Ok(());
+        if found_images.is_empty() {
+            warn!("No remote images found for: {}", config.Name);
+            return Ok(Update::Nothing(UpdateStatus::Skipped));
         }
-        debug!("Found potential sysexts:\n{parsed_sha256sums}");
+        debug!("Found potential sysexts:\n{found_images}");
 
         // Search latest image from SHA256SUM list that matches arch & version_id
-        let remote_image = match self.find_latest_image(&config.Name, &remote_images)? {
+        let remote_image = match self.find_latest_image(config, &remote_images, version_id)? {
             None => {
                 error!("No remote valid image found for sysext: {}", config.Name);
-                return Ok(());
+                return Ok(Update::Nothing(UpdateStatus::Skipped));
             }
             Some(i) => {
                 debug!(
@@ -479,7 +778,7 @@ impl Manager {
         };
 
         // Compare it with the latest image installed locally, matching arch & version_id
-        let download_image = match self.find_latest_image(&config.Name, images)? {
+        let download_image = match self.find_latest_image(config, images, version_id)? {
             None => {
                 info!(
                     "No local image for sysext: {}. Downloading: {}",
@@ -498,49 +797,390 @@ impl Manager {
                         remote_image
                     }
                     Ok(Cmp::Eq) => {
-                        println!("No update found for '{}'", img.name);
-                        // TODO: Compute local image SHA256SUM, compare it and warn if different
-                        return Ok(());
+                        if self.check_local_image(&img, &remote_image)? {
+                            info!("No update found for '{}'", img.name);
+                            return Ok(Update::Nothing(UpdateStatus::UpToDate));
+                        }
+                        if !repair {
+                            warn!(
+                                "Not downloading '{}' again (use --repair to replace it)",
+                                img.path()
+                            );
+                            return Ok(Update::Nothing(UpdateStatus::Skipped));
+                        }
+                        info!("Repairing image: {}", img.path());
+                        remote_image
                     }
                     Ok(Cmp::Gt) => {
                         warn!("Local image is newer for '{}': {}", img.name, img.version);
-                        return Ok(());
+                        return Ok(Update::Nothing(UpdateStatus::UpToDate));
                     }
                     _ => {
-                        return Err(anyhow!(
+                        return Err(Error::InvalidImage(format!(
                             "Invalid version number: {} or {}",
-                            img.version,
-                            remote_image.version
-                        ));
+                            img.version, remote_image.version
+                        )));
                     }
                 }
             }
         };
+        Ok(Update::Download(download_image))
+    }
+
+    /// Size of an image on the main URL for a sysext, if known
+    fn image_size(&self, config: &Config, image: &Image) -> Option<u64> {
+        let size = (|| -> Result<Option<u64>> {
+            let policy = self.retry_policy();
+            let client = self.client(config, &config.Url)?;
+            if oci::is_oci(&config.Url) {
+                oci::Registry::connect(&client, &config.Url, &policy)?.size(image)
+            } else {
+                let url = format!("{}/{}/{}", config.Url, config.Name, image.path());
+                download::size(&client, &url, &HeaderMap::new(), &policy)
+            }
+        })();
+        size.unwrap_or_else(|e| {
+            warn!("Could not get size for {}: {e:#}", image.path());
+            None
+        })
+    }
 
-        println!("Downloading update: {}", download_image.path());
-        let image_url = format!("{}/{}/{}", config.Url, config.Name, download_image.path());
-        debug!("Downloading: {image_url}");
-        let mut response = client.get(image_url).send()?;
+    /// List the images available for a sysext, either from the SHA256SUMS
+    /// file or from the tags in an OCI registry
+    fn remote_images(&self, config: &Config) -> Result<Vec<Image>> {
+        if oci::is_oci(&config.Url) {
+            let policy = self.retry_policy();
+            let client = self.client(config, &config.Url)?;
+            let registry = oci::Registry::connect(&client, &config.Url, &policy)?;
+            return registry.images(&config.Name);
+        }
 
-        // Setup a temporary file
-        let sysext_store = self.rootdir.join(DEFAULT_STORE);
-        let sysext_tmp = sysext_store.join(format!("{}.tmp", download_image.path()));
-        let file = File::create(&sysext_tmp)?;
+        debug!("Downloading SHA256SUMS for: {}", config.Name);
+        let sha256sums = self.fetch_sha256sums(config)?;
+        debug!("{sha256sums}");
+
+        // Parse images and hashes list from SHA256SUM file
+        let mut remote_images: Vec<Image> = Vec::new();
+        for line in sha256sums.lines() {
+            let mut split = line.split("  ");
+            let hash: String = match split.next() {
+                Some(s) => s.into(),
+                None => {
+                    error!("Invalid line in SHA256SUMS file: {line}");
+                    continue;
+                }
+            };
+            let filename: String = match split.next() {
+                Some(s) => s.into(),
+                None => {
+                    error!("Invalid line in SHA256SUMS file: {line}");
+                    continue;
+                }
+            };
+            let Ok(image) = Image::new(&config.Name, filename.clone().into(), Some(hash)) else {
+                warn!("Ignoring invalid sysext: {filename}");
+                continue;
+            };
+            remote_images.push(image);
+        }
+        Ok(remote_images)
+    }
+
+    /// Fetch the SHA256SUMS file for a sysext, verifying its signature if
+    /// enabled. Mirrors are only used if we can verify the signature as we
+    /// would otherwise have no way to know if we can trust their content.
+    fn fetch_sha256sums(&self, config: &Config) -> Result<String> {
+        let urls = if config.Verify {
+            config.urls()
+        } else {
+            vec![config.Url.as_str()]
+        };
+        let mut error = None;
+        for url in urls {
+            match self.fetch_sha256sums_from(config, url) {
+                Ok(s) => return Ok(s),
+                Err(e) => {
+                    warn!(
+                        "Could not get SHA256SUMS for {} from {url}: {e}",
+                        config.Name
+                    );
+                    self.report_verification(&config.Name, &e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if !config.Verify && !config.Mirrors.is_empty() {
+            info!(
+                "Not using mirrors for SHA256SUMS for {} as signature verification is disabled",
+                config.Name
+            );
+        }
+        // Errors for the mirrors have been logged above
+        Err(error.unwrap_or_else(|| Error::Config(format!("No URL for: {}", config.Name))))
+    }
+
+    fn fetch_sha256sums_from(&self, config: &Config, url: &str) -> Result<String> {
+        let client = self.client(config, url)?;
+        let sha256sum_url = format!("{url}/{}/SHA256SUMS", config.Name);
+        debug!("Downloading: {sha256sum_url}");
+        let sha256sums = download::get_text(&client, &sha256sum_url, &self.retry_policy())?;
+
+        if config.Verify {
+            let signature_url = format!("{sha256sum_url}.gpg");
+            debug!("Downloading: {signature_url}");
+            let signature = download::get_bytes(&client, &signature_url, &self.retry_policy())?;
+            let keyring = self.find_keyring(config)?;
+            gpg::verify(&keyring, sha256sums.as_bytes(), &signature).map_err(|e| match e {
+                Error::Signature(e) => Error::Signature(format!(
+                    "Invalid signature for SHA256SUMS for {}: {e}",
+                    config.Name
+                )),
+                e => e,
+            })?;
+            info!("Valid signature for SHA256SUMS for: {}", config.Name);
+        }
+        Ok(sha256sums)
+    }
+
+    /// Build the HTTP client to use for a sysext from the global and the
+    /// sysext specific network settings. Credentials are only sent to the
+    /// main URL and never to mirrors.
+    fn client(&self, config: &Config, url: &str) -> Result<Client> {
+        let network = config.Network.or(&self.global.Network);
+        let mut builder = Client::builder();
+        if let Some(auth) = config.Auth.as_ref().filter(|_| url == config.Url) {
+            let secret =
+                credentials::load(&self.root, &self.rootdir, auth).map_err(|e| match e {
+                    Error::Credentials(e) => Error::Credentials(format!(
+                        "Could not load credentials for {}: {e}",
+                        config.Name
+                    )),
+                    e => e,
+                })?;
+            let value = match config.AuthType {
+                AuthType::Bearer => HeaderValue::from_str(&format!("Bearer {secret}")),
+                AuthType::Basic => {
+                    HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(secret)))
+                }
+            };
+            let mut value = value.map_err(|_| {
+                Error::Credentials(format!("Invalid credentials for: {}", config.Name))
+            })?;
+            // Make sure that it does not show up in debug output
+            value.set_sensitive(true);
+            builder = builder.default_headers(HeaderMap::from_iter([(AUTHORIZATION, value)]));
+        }
+        if let Some(proxy) = &network.Proxy {
+            debug!("Using proxy for {}: {proxy}", config.Name);
+            let no_proxy = network.NoProxy.as_deref().and_then(NoProxy::from_string);
+            let proxy = Proxy::all(proxy).map_err(|e| {
+                Error::Config(format!(
+                    "Invalid Proxy for {}: {proxy}: {}",
+                    config.Name,
+                    causes(&e)
+                ))
+            })?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy));
+        }
+        if let Some(path) = &network.CACertificates {
+            let pem = self.read_file(Path::new(path))?;
+            let certs = Certificate::from_pem_bundle(&pem).map_err(|e| {
+                Error::Config(format!(
+                    "Invalid CACertificates for {}: {path}: {}",
+                    config.Name,
+                    causes(&e)
+                ))
+            })?;
+            builder = builder.tls_certs_merge(certs);
+        }
+        match (&network.ClientCertificate, &network.ClientKey) {
+            (None, None) => {}
+            (Some(cert), Some(key)) => {
+                let cert_pem = self.read_file(Path::new(cert))?;
+                let key_pem = self.read_file(Path::new(key))?;
+                let identity = Identity::from_pkcs8_pem(&cert_pem, &key_pem).map_err(|e| {
+                    Error::Config(format!(
+                        "Invalid ClientCertificate or ClientKey for {}: {cert}, {key}: {}",
+                        config.Name,
+                        causes(&e)
+                    ))
+                })?;
+                // Client certificates are only supported with native-tls
+                builder = builder.tls_backend_native().identity(identity);
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "ClientCertificate and ClientKey must be set together for: {}",
+                    config.Name
+                )));
+            }
+        }
+        // Invalid settings may only be reported when building the client
+        builder.build().map_err(|e| {
+            Error::Config(format!(
+                "Invalid network settings for {}: {}",
+                config.Name,
+                causes(&e)
+            ))
+        })
+    }
+
+    fn retry_policy(&self) -> download::RetryPolicy {
+        download::RetryPolicy {
+            retries: self.global.Retries,
+            delay: Duration::from_secs(self.global.RetryDelay),
+        }
+    }
+
+    /// Find and read the keyring to use to verify signatures for a sysext
+    fn find_keyring(&self, config: &Config) -> Result<Vec<u8>> {
+        let name = config.Keyring.as_deref().unwrap_or("default");
+        for dir in ALL_CONFIG_DIRS {
+            let keyring = Path::new(dir).join("keys").join(format!("{name}.gpg"));
+            if self.root.exists(&keyring) {
+                debug!("Using keyring: {}", self.rootdir.join(&keyring).display());
+                return self.read_file(&keyring);
+            }
+        }
+        Err(Error::Config(format!(
+            "Could not find keyring '{name}' to verify signatures for: {}",
+            config.Name
+        )))
+    }
+
+    /// Report hash and signature verification failures
+    fn report_verification(&self, image: &str, error: &Error) {
+        if matches!(error, Error::HashMismatch { .. } | Error::Signature(_)) {
+            self.reporter
+                .report(&Event::VerificationFailed { image, error });
+        }
+    }
+
+    /// Compare the hash of a local image with the one published in the
+    /// SHA256SUMS file. Returns false if they differ.
+    fn check_local_image(&self, local: &Image, remote: &Image) -> Result<bool> {
+        let image = Path::new(DEFAULT_STORE).join(local.path());
+        let path = self.rootdir.join(&image);
+        debug!("Computing hash for local image: {}", path.display());
+        let digest = sha256sum(&mut self.root.open(&image).map_err(error::io(&path))?)?;
+        let expected = remote.hash.clone().unwrap_or("?".into());
+        if digest == expected {
+            debug!("Valid hash for local image {} {digest}", local.path());
+            return Ok(true);
+        }
+        warn!(
+            "Local image does not match published hash: {}: got {digest} vs expected {expected}",
+            path.display()
+        );
+        self.report_verification(
+            &local.path(),
+            &Error::HashMismatch {
+                image: local.path(),
+                expected,
+                actual: digest,
+            },
+        );
+        Ok(false)
+    }
+
+    /// Download an image to a temporary file, verify its hash and then
+    /// atomically move it into the store
+    fn download_image(&self, config: &Config, download_image: &Image) -> Result<()> {
+        info!("Downloading update: {}", download_image.path());
+        let mut transfer = ImageTransfer {
+            manager: self,
+            image: download_image.path(),
+            position: 0,
+            total: None,
+        };
+        let mut error = None;
+        for url in config.urls() {
+            match self.download_image_from(url, config, download_image, &mut transfer) {
+                Ok(()) => {
+                    transfer.finish(true);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not download {} from {url}: {e}",
+                        download_image.path()
+                    );
+                    self.report_verification(&download_image.path(), &e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        transfer.finish(false);
+        // Errors for the mirrors have been logged above
+        Err(error.unwrap_or_else(|| Error::Config(format!("No URL for: {}", config.Name))))
+    }
+
+    fn download_image_from(
+        &self,
+        url: &str,
+        config: &Config,
+        download_image: &Image,
+        transfer: &mut dyn Transfer,
+    ) -> Result<()> {
+        let client = self.client(config, url)?;
+        // Download to a temporary file, resuming any previous partial
+        // download, and compute the sha256sum hash as we go
+        let sysext_store = self.root.open_dir(DEFAULT_STORE)?;
+        // The partial download uses a fixed name, and not an anonymous
+        // (O_TMPFILE) or randomly named file, so that a later run can find it
+        // and resume the download where it stopped.
+        let tmp_name = format!("{}.tmp", download_image.path());
+
+        // As the name is shared, lock the partial download so that
+        // concurrent runs can not write to it at the same time. The lock is
+        // released once it is installed.
+        let mut file = sysext_store
+            .open_with(
+                &tmp_name,
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .mode(IMAGE_MODE),
+            )?
+            .into_std();
+        // The previous holder of the lock renames the file once the download
+        // is complete, possibly after we opened it
+        let locked = flock(&file, FlockOperation::NonBlockingLockExclusive).is_ok()
+            && match (file.metadata(), sysext_store.metadata(&tmp_name)) {
+                (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+                _ => false,
+            };
+        if !locked {
+            return Err(Error::Conflict(format!(
+                "{} is already being downloaded by another process",
+                download_image.path()
+            )));
+        }
 
-        // Create a writer to compute sha256sum hash as we download the image to the temporary file
-        let mut writer = Sha256Writer::new(file);
-        response.copy_to(&mut writer)?;
+        let policy = self.retry_policy();
+        let digest = if oci::is_oci(url) {
+            let registry = oci::Registry::connect(&client, url, &policy)?;
+            registry.download(download_image, &mut file, transfer)?
+        } else {
+            let image_url = format!("{url}/{}/{}", config.Name, download_image.path());
+            debug!("Downloading: {image_url}");
+            let headers = HeaderMap::new();
+            download::download(&client, &image_url, &headers, &mut file, &policy, transfer)?
+        };
 
-        let digest = writer.digest();
         if digest != download_image.hash.clone().unwrap_or("?".into()) {
-            debug!("Invalid hash, removing file: {}", sysext_tmp.display());
-            remove_file(sysext_tmp)?;
-            return Err(anyhow!(
-                "Invalid hash for {}: got {} vs expected {}",
-                download_image.path(),
-                digest,
-                download_image.hash.unwrap_or("?".into())
-            ));
+            debug!(
+                "Invalid hash, removing file: {}",
+                self.rootdir.join(DEFAULT_STORE).join(&tmp_name).display()
+            );
+            sysext_store.remove_file(&tmp_name)?;
+            return Err(Error::HashMismatch {
+                image: download_image.path(),
+                expected: download_image.hash.clone().unwrap_or("?".into()),
+                actual: digest,
+            });
         }
         debug!(
             "Valid hash for {} {}",
@@ -548,58 +1188,848 @@ impl Manager {
             download_image.hash.clone().unwrap_or("?".into())
         );
 
-        // Rename to final name
-        let sysext_final = sysext_store.join(download_image.path());
-        debug!(
-            "Renaming: {} -> {}",
-            sysext_tmp.display(),
-            sysext_final.display()
-        );
-        rename(sysext_tmp, &sysext_final)?;
-        println!("Successfully updated sysext: {}", config.Name);
+        install(&sysext_store, &file, &tmp_name, &download_image.path())
+    }
 
-        // TODO: Add image to manager
+    /// Update all sysexts. With `repair`, local images that do not match the
+    /// published hash are downloaded again. Errors for a sysext are logged
+    /// and returned with the results for each sysext and release.
+    pub fn update(&mut self, repair: bool) -> Result<Vec<Updated>> {
+        info!("Updating all sysexts");
+        self.update_sysexts(None, repair)
+    }
 
-        Ok(())
+    /// Update a single sysext
+    pub fn update_one(&mut self, name: &str, repair: bool) -> Result<Vec<Updated>> {
+        if !self.configs.contains_key(name) {
+            return Err(Error::NoConfig(name.to_string()));
+        }
+        info!("Updating sysext: {name}");
+        self.update_sysexts(Some(name), repair)
     }
 
-    pub fn update(&self) -> Result<()> {
-        info!("Updating all sysexts");
+    /// Update all sysexts, or only the one named, and reload the images from
+    /// the store as downloads may have replaced or pruned some of them
+    fn update_sysexts(&mut self, name: Option<&str>, repair: bool) -> Result<Vec<Updated>> {
+        let _lock = self.lock()?;
+        self.create_store()?;
+
+        let empty: Vec<Image> = vec![];
+        let mut updated: Vec<Updated> = self
+            .updates()
+            .into_par_iter()
+            .filter(|(n, _, _)| name.is_none_or(|name| name == n))
+            .map(|(n, c, v)| {
+                let images = self.images.get(&n).unwrap_or(&empty);
+                self.update_sysext(&c, images, &v, repair)
+            })
+            .collect();
+        self.images.clear();
+        self.load_images()?;
+        updated.sort_by(|a, b| (&a.name, &a.version_id).cmp(&(&b.name, &b.version_id)));
+        Ok(updated)
+    }
+
+    /// Changes that `update` would make: the images to download. With
+    /// `clean`, also the images that would be removed afterwards, keeping
+    /// the given number of images. Errors for a sysext are logged and the
+    /// sysext is skipped, as with `update`.
+    pub fn plan_update(&self, repair: bool, clean: Option<usize>) -> Result<Plan> {
+        let empty: Vec<Image> = vec![];
+        let downloads: Vec<Change> = self
+            .updates()
+            .into_par_iter()
+            .filter_map(|(n, c, v)| {
+                let images = self.images.get(&n).unwrap_or(&empty);
+                match self.find_update(&c, images, &v, repair) {
+                    Ok(Update::Download(image)) => Some(Change::Download {
+                        name: n,
+                        image: image.path(),
+                        size: self.image_size(&c, &image),
+                        hash: image.hash.unwrap_or_default(),
+                    }),
+                    Ok(Update::Nothing(_)) => None,
+                    Err(e) => {
+                        error!("Failed to update sysext: {n} (VERSION_ID: {v}): {e}");
+                        None
+                    }
+                }
+            })
+            .collect();
 
+        let mut changes = downloads.clone();
+        if let Some(keep) = clean {
+            // Clean up as if the downloads were done
+            let mut images = self.scan_images();
+            for change in &downloads {
+                if let Change::Download {
+                    name, image, hash, ..
+                } = change
+                {
+                    let image = Image::new(name, image.into(), Some(hash.clone()))?;
+                    images.entry(name.clone()).or_default().push(image);
+                }
+            }
+            changes.extend(self.clean_changes(&images, keep)?);
+        }
+        Ok(Plan::new(changes))
+    }
+
+    /// Create the store if needed
+    fn create_store(&self) -> Result<()> {
         let sysext_store = self.rootdir.join(DEFAULT_STORE);
-        if !sysext_store.exists() {
+        if !self.root.exists(DEFAULT_STORE) {
             debug!("Creating {}", &sysext_store.display());
-            fs::create_dir(&sysext_store)?;
+            self.root
+                .create_dir_with(DEFAULT_STORE, DirBuilder::new().mode(0o755))?;
+        }
+        if !self.root.metadata(DEFAULT_STORE)?.is_dir() {
+            return Err(Error::Conflict(format!(
+                "{} is not a directory",
+                &sysext_store.display()
+            )));
         }
-        if !fs::metadata(&sysext_store)?.is_dir() {
-            return Err(anyhow!("{} is not a directory", &sysext_store.display()));
+        Ok(())
+    }
+
+    /// Sysexts to update, for each release: the current one and the one of
+    /// the staged deployment, if any
+    fn updates(&self) -> Vec<(String, Config, String)> {
+        // Also download images for the staged deployment, if any, so that
+        // they are ready when we reboot into it
+        let mut version_ids = vec![self.system.version_id.clone()];
+        for d in self.deployments().iter().filter(|d| d.staged) {
+            match d.version_id(&self.root, &self.rootdir) {
+                Ok(v) => {
+                    if !version_ids.contains(&v) {
+                        info!("Also updating sysexts for staged deployment (VERSION_ID: {v})");
+                        version_ids.push(v);
+                    }
+                }
+                Err(e) => warn!("Could not find VERSION_ID for staged deployment: {e}"),
+            }
         }
 
-        let empty: Vec<Image> = vec![];
-        self.configs.clone().into_par_iter().for_each(|(n, c)| {
-            let images = self.images.get(&n).unwrap_or(&empty);
-            self.update_sysext(&c, images).unwrap_or_else(|e| {
-                error!("Failed to update sysext: {n}: {e}");
+        self.configs
+            .iter()
+            .flat_map(|(n, c)| {
+                version_ids
+                    .iter()
+                    .map(|v| (n.clone(), c.clone(), v.clone()))
+            })
+            .collect()
+    }
+
+    /// List the deployments on disk, if the system is managed by a deployment
+    /// backend (rpm-ostree or bootc). Errors are logged and ignored.
+    pub fn deployments(&self) -> Vec<Deployment> {
+        let Some(backend) = &self.backend else {
+            return vec![];
+        };
+        match backend.deployments() {
+            Ok(d) => d,
+            Err(e) => {
+                warn!(
+                    "Could not get deployments status from {}: {e}",
+                    backend.name()
+                );
+                vec![]
+            }
+        }
+    }
+
+    /// Names of all images currently enabled via a symlink in any of the
+    /// extensions directories, including the ones from other deployments.
+    fn enabled_images(&self) -> HashSet<String> {
+        let mut roots = vec![PathBuf::new()];
+        for d in self.deployments() {
+            roots.push(d.path);
+        }
+        let mut enabled = HashSet::new();
+        for root in roots {
+            for ext_type in [ExtensionType::Sysext, ExtensionType::Confext] {
+                for dir in ext_type.extensions_dirs() {
+                    let dir = relative_path(&root).join(dir);
+                    let Ok(entries) = self.root.read_dir(&dir) else {
+                        continue;
+                    };
+                    for entry in entries.flatten() {
+                        let path = dir.join(entry.file_name());
+                        let Ok(target) = self.root.read_link_contents(&path) else {
+                            continue;
+                        };
+                        if let Some(image) = target.file_name() {
+                            debug!(
+                                "Found enabled image: {} -> {}",
+                                self.rootdir.join(&path).display(),
+                                target.display()
+                            );
+                            enabled.insert(image.to_string_lossy().into_owned());
+                        }
+                    }
+                }
+            }
+        }
+        enabled
+    }
+
+    /// Remove old images from the store, keeping the `keep` newest images for
+    /// each sysext and release. Images that are currently enabled or that
+    /// would be enabled for one of the deployments on disk are always kept.
+    /// If we know all the deployments on disk, images for releases that are
+    /// not used by any deployment are removed.
+    pub fn clean(&mut self, keep: usize) -> Result<Cleaned> {
+        let _lock = self.lock()?;
+        info!("Cleaning up old images (keeping {keep} per release)");
+
+        // Make sure that we know about all images in the store
+        self.images.clear();
+        self.load_images()?;
+
+        let mut cleaned = Cleaned {
+            files: vec![],
+            images: 0,
+            reclaimed: 0,
+        };
+        for change in self.clean_changes(&self.images, keep)? {
+            let Change::Delete { path, size } = &change else {
+                continue;
+            };
+            if path.extension().is_some_and(|e| e == "tmp") {
+                info!(
+                    "Removing partial download: {} ({size} bytes)",
+                    path.display()
+                );
+            } else {
+                info!("Removing image: {} ({size} bytes)", path.display());
+                cleaned.images += 1;
+            }
+            self.apply_change(&change)?;
+            cleaned.files.push(path.clone());
+            cleaned.reclaimed += size;
+        }
+        self.images.clear();
+        self.load_images()?;
+
+        Ok(cleaned)
+    }
+
+    /// Changes that `clean` would make: the images and partial downloads to
+    /// delete
+    pub fn plan_clean(&self, keep: usize) -> Result<Plan> {
+        Ok(Plan::new(self.clean_changes(&self.scan_images(), keep)?))
+    }
+
+    fn clean_changes(
+        &self,
+        images: &HashMap<String, Vec<Image>>,
+        keep: usize,
+    ) -> Result<Vec<Change>> {
+        let mut changes = vec![];
+        for image in self.removable_images(images, keep)? {
+            let path = system_path(DEFAULT_STORE).join(image.path());
+            // Images that are not downloaded yet when planning an update
+            let Ok(metadata) = self.root.symlink_metadata(relative_path(&path)) else {
+                continue;
+            };
+            changes.push(Change::Delete {
+                path,
+                size: metadata.len(),
             });
-        });
+        }
+        for path in self.stale_partial_downloads(images)? {
+            let size = self.root.symlink_metadata(&path)?.len();
+            changes.push(Change::Delete {
+                path: system_path(&path),
+                size,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// Images that can be removed: images for other architectures or for
+    /// releases that are not deployed, and old images, keeping the `keep`
+    /// newest ones per sysext and release. Images that would be enabled for
+    /// one of the releases in use and enabled images are never removed.
+    fn removable_images(
+        &self,
+        images: &HashMap<String, Vec<Image>>,
+        keep: usize,
+    ) -> Result<Vec<Image>> {
+        let enabled = self.enabled_images();
+
+        let deployments = self.deployments();
+        let mut version_ids = vec![self.system.version_id.clone()];
+        for d in &deployments {
+            match d.version_id(&self.root, &self.rootdir) {
+                Ok(v) => {
+                    if !version_ids.contains(&v) {
+                        version_ids.push(v);
+                    }
+                }
+                Err(e) => {
+                    // Be conservative as we do not know which release is
+                    // used by this deployment
+                    warn!("Could not find VERSION_ID for deployment: {e}");
+                    return Err(Error::System(
+                        "Not cleaning up: unknown deployment release".into(),
+                    ));
+                }
+            }
+        }
+        let known_deployments = !deployments.is_empty();
+        debug!("Releases in use: {}", version_ids.join(", "));
+
+        let mut to_remove: Vec<Image> = vec![];
+        for (name, images) in images {
+            let Some(config) = self.configs.get(name) else {
+                continue;
+            };
+
+            // Images that would be enabled for each release in use
+            let mut selected = HashSet::new();
+            for v in &version_ids {
+                if let Some(i) = self.find_latest_image(config, images, v)? {
+                    selected.insert(i.path());
+                }
+            }
+
+            let mut groups: HashMap<String, Vec<&Image>> = HashMap::new();
+            for image in images {
+                if image.architecture != self.system.arch {
+                    debug!("Removing '{}' (incompatible architecture)", image.path());
+                    to_remove.push(image.clone());
+                    continue;
+                }
+                if known_deployments && !version_ids.contains(&image.version_id) {
+                    debug!("Removing '{}' (release not deployed)", image.path());
+                    to_remove.push(image.clone());
+                    continue;
+                }
+                groups
+                    .entry(image.version_id.clone())
+                    .or_default()
+                    .push(image);
+            }
+
+            for (_, mut group) in groups {
+                // Newest first
+                group.sort_by(|a, b| {
+                    compare(&b.version, &a.version)
+                        .ok()
+                        .and_then(|c| c.ord())
+                        .unwrap_or(Ordering::Equal)
+                });
+                for image in group.into_iter().skip(keep) {
+                    if !selected.contains(&image.path()) {
+                        to_remove.push(image.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(to_remove
+            .into_iter()
+            .filter(|image| {
+                if enabled.contains(&image.path()) {
+                    info!("Keeping currently enabled image: {}", image.path());
+                    return false;
+                }
+                true
+            })
+            .collect())
+    }
+
+    /// Make sure that there is enough space for a download of `needed` bytes,
+    /// both on the store filesystem and within the store quota, removing old
+    /// images if needed
+    fn check_space(&self, name: &str, needed: u64) -> Result<()> {
+        let _guard = self.space.lock().unwrap();
+        let sysext_store = self.rootdir.join(DEFAULT_STORE);
 
-        println!("Successfully updated all sysexts");
+        if let Some(quota) = self.global.store_quota()? {
+            let used = self.store_usage()?;
+            if used + needed > quota {
+                info!("Store quota exceeded by downloading {name}. Removing old images");
+                self.prune(used + needed - quota)?;
+                let used = self.store_usage()?;
+                if used + needed > quota {
+                    return Err(Error::NoSpace(format!(
+                        "Not enough space left in the store quota to download {name}: {needed} bytes needed, {} bytes available",
+                        quota.saturating_sub(used)
+                    )));
+                }
+            }
+        }
+
+        let store = self.root.open_dir(DEFAULT_STORE)?;
+        let available = available_space(&store).map_err(error::io(&sysext_store))?;
+        if needed > available {
+            info!("Not enough free space to download {name}. Removing old images");
+            self.prune(needed - available)?;
+            let available = available_space(&store).map_err(error::io(&sysext_store))?;
+            if needed > available {
+                return Err(Error::NoSpace(format!(
+                    "Not enough free space to download {name}: {needed} bytes needed, {available} bytes available"
+                )));
+            }
+        }
         Ok(())
     }
 
-    pub fn status(&self) -> Result<()> {
-        println!("sysexts:");
-        for (n, c) in &self.configs {
-            println!("  {n} ({}, {}):", c.Kind, c.Url);
-            match self.images.get(n) {
-                None => println!("    No images installed for that sysext"),
-                Some(images) => {
-                    for i in images {
-                        println!("    {}", i.path())
+    /// Total size of the files in the store, including partial downloads
+    fn store_usage(&self) -> Result<u64> {
+        let mut used = 0;
+        for file in self.root.read_dir(DEFAULT_STORE)? {
+            let metadata = file?.metadata()?;
+            if metadata.is_file() {
+                used += metadata.len();
+            }
+        }
+        Ok(used)
+    }
+
+    /// Remove old images, the same way `clean` does but keeping only the
+    /// newest image per sysext and release, until `needed` bytes are freed.
+    /// Returns the number of bytes freed.
+    fn prune(&self, needed: u64) -> Result<u64> {
+        let sysext_store = self.rootdir.join(DEFAULT_STORE);
+        let mut freed = 0;
+        for image in self.removable_images(&self.scan_images(), 1)? {
+            if freed >= needed {
+                break;
+            }
+            let path = Path::new(DEFAULT_STORE).join(image.path());
+            let Ok(metadata) = self.root.symlink_metadata(&path) else {
+                continue;
+            };
+            info!(
+                "Removing image to free space: {} ({} bytes)",
+                sysext_store.join(image.path()).display(),
+                metadata.len()
+            );
+            self.root.remove_file(&path)?;
+            freed += metadata.len();
+        }
+        debug!("Freed {freed} bytes ({needed} bytes needed)");
+        Ok(freed)
+    }
+
+    /// Partial downloads in the store that will not be resumed: the ones that
+    /// are not for a configured sysext and the ones for which we already have
+    /// the same or a newer image. Paths are relative to the root directory.
+    fn stale_partial_downloads(
+        &self,
+        images: &HashMap<String, Vec<Image>>,
+    ) -> Result<Vec<PathBuf>> {
+        let Ok(files) = self.root.read_dir(DEFAULT_STORE) else {
+            return Ok(vec![]);
+        };
+        let mut stale = vec![];
+        for file in files.flatten() {
+            let filename = file.file_name().to_string_lossy().into_owned();
+            let Some(image_name) = filename.strip_suffix(".tmp") else {
+                continue;
+            };
+            let partial = self
+                .configs
+                .keys()
+                .filter(|name| image_name.starts_with(name.as_str()))
+                .find_map(|name| Image::new(name, image_name.into(), None).ok());
+            let path = Path::new(DEFAULT_STORE).join(file.file_name());
+            let Some(partial) = partial else {
+                stale.push(path);
+                continue;
+            };
+            let superseded = images.get(&partial.name).is_some_and(|images| {
+                images.iter().any(|i| {
+                    i.architecture == partial.architecture
+                        && i.version_id == partial.version_id
+                        && matches!(compare(&i.version, &partial.version), Ok(Cmp::Eq | Cmp::Gt))
+                })
+            });
+            if superseded {
+                stale.push(path);
+            }
+        }
+        Ok(stale)
+    }
+
+    /// Apply a plan, usually computed by a previous dry run and reviewed in
+    /// the meantime. Changes are checked and applied in order, stopping at
+    /// the first error. Downloaded images are verified against the hash
+    /// recorded in the plan.
+    pub fn apply(&mut self, plan: &Plan) -> Result<()> {
+        let _lock = self.lock()?;
+        let enabled = self.enabled_images();
+        for change in &plan.changes {
+            self.check_change(change, &enabled)?;
+        }
+        for change in &plan.changes {
+            info!("{change}");
+            self.apply_change(change)?;
+            match change {
+                Change::Link { name, target, .. } => {
+                    self.reporter.report(&Event::Enabled(&Enabled {
+                        name: name.clone(),
+                        ext_type: self.ext_type(name),
+                        image: image_name(target).unwrap_or_default(),
+                    }));
+                }
+                Change::Unlink { name, target, .. } => {
+                    self.reporter.report(&Event::Disabled(&Disabled {
+                        name: name.clone(),
+                        ext_type: self.ext_type(name),
+                        image: target.as_deref().and_then(image_name),
+                    }));
+                }
+                Change::Download { .. } | Change::Delete { .. } => {}
+            }
+        }
+        self.images.clear();
+        self.load_images()?;
+        Ok(())
+    }
+
+    /// Make sure that a change from a plan only touches the files that we
+    /// manage and that the system did not change since the plan was made:
+    /// symlinks still point to the `previous` image and images to delete are
+    /// not enabled
+    fn check_change(&self, change: &Change, enabled: &HashSet<String>) -> Result<()> {
+        let in_dirs = |path: &Path, dirs: &[&str]| {
+            path.parent()
+                .is_some_and(|p| dirs.iter().any(|d| p == system_path(d)))
+        };
+        let invalid = || Error::Plan(format!("Invalid change in plan: {change}"));
+        match change {
+            Change::Download { name, image, .. } => {
+                if !self.configs.contains_key(name) {
+                    return Err(Error::NoConfig(name.clone()));
+                }
+                if image.contains('/') {
+                    return Err(invalid());
+                }
+                Image::new(name, image.into(), None)?;
+            }
+            Change::Link {
+                name,
+                path,
+                target,
+                previous,
+            } => {
+                let runtime_dir = self.ext_type(name).runtime_dir();
+                let image = target
+                    .strip_prefix("../../var/lib/extensions.d")
+                    .map_err(|_| invalid())?;
+                if !in_dirs(path, &[runtime_dir])
+                    || path.file_name() != Some(format!("{name}.raw").as_ref())
+                    || image.components().count() != 1
+                {
+                    return Err(invalid());
+                }
+                let current = self.root.read_link_contents(relative_path(path)).ok();
+                if current != *previous {
+                    return Err(Error::Plan(format!(
+                        "{} changed since the plan was made: now {}",
+                        self.root_path(path).display(),
+                        current
+                            .map(|c| format!("-> {}", c.display()))
+                            .unwrap_or("missing".into())
+                    )));
+                }
+            }
+            Change::Unlink { name, path, .. } => {
+                let runtime_dir = self.ext_type(name).runtime_dir();
+                if !in_dirs(path, &[runtime_dir])
+                    || path.file_name() != Some(format!("{name}.raw").as_ref())
+                {
+                    return Err(invalid());
+                }
+            }
+            Change::Delete { path, .. } => {
+                let mut dirs = vec![DEFAULT_STORE];
+                dirs.extend(MUTABLE_CONFIG_DIRS);
+                if !in_dirs(path, &dirs) {
+                    return Err(invalid());
+                }
+                let image = path.file_name().map(|f| f.to_string_lossy());
+                if in_dirs(path, &[DEFAULT_STORE])
+                    && image.is_some_and(|i| enabled.contains(i.as_ref()))
+                {
+                    return Err(Error::Plan(format!(
+                        "Not deleting an enabled image: {}",
+                        self.root_path(path).display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_change(&self, change: &Change) -> Result<()> {
+        match change {
+            Change::Download {
+                name, image, hash, ..
+            } => {
+                let config = self
+                    .configs
+                    .get(name)
+                    .ok_or_else(|| Error::NoConfig(name.clone()))?;
+                if self.root.exists(Path::new(DEFAULT_STORE).join(image)) {
+                    info!("Already downloaded: {image}");
+                    return Ok(());
+                }
+                let image = Image::new(name, image.into(), Some(hash.clone()))?;
+                self.create_store()?;
+                self.download_image(config, &image)?;
+            }
+            Change::Link {
+                name, path, target, ..
+            } => {
+                let link = relative_path(path);
+                self.create_runtime_dir(self.ext_type(name))?;
+                if let Ok(m) = self.root.symlink_metadata(link) {
+                    if !m.file_type().is_symlink() {
+                        return Err(Error::Conflict(format!(
+                            "Not overriding an existing file for: {}",
+                            self.root_path(path).display()
+                        )));
                     }
+                    self.root.remove_file(link)?;
+                }
+                self.root.symlink(target, link)?;
+            }
+            Change::Unlink { path, .. } | Change::Delete { path, .. } => {
+                let path = relative_path(path);
+                if self.root.symlink_metadata(path).is_ok() {
+                    self.root.remove_file(path)?;
                 }
+            }
+        }
+        Ok(())
+    }
+
+    /// Path under the root directory for a path on the target system, for
+    /// messages
+    fn root_path(&self, path: &Path) -> PathBuf {
+        self.rootdir.join(relative_path(path))
+    }
+
+    /// Read a file from the target system, for example a certificate
+    fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        self.root
+            .read(relative_path(path))
+            .map_err(error::io(&self.root_path(path)))
+    }
+
+    /// Types of extensions that have a configuration
+    pub fn extension_types(&self) -> Vec<ExtensionType> {
+        let mut types: Vec<ExtensionType> = vec![];
+        for c in self.configs.values() {
+            if !types.contains(&c.Type) {
+                types.push(c.Type);
+            }
+        }
+        types
+    }
+
+    /// State of all configured sysexts: configuration, images in the store
+    /// and enabled image, sorted by name
+    pub fn status(&self) -> Result<Status> {
+        let empty: Vec<Image> = vec![];
+        let mut sysexts = vec![];
+        for (n, c) in &self.configs {
+            let images = self.images.get(n).unwrap_or(&empty);
+            let error = match self.find_latest_image(c, images, &self.system.version_id) {
+                Ok(Some(_)) => None,
+                Ok(None) => Some(format!("No image to enable for {}: {n}", c.Type)),
+                Err(e) => Some(format!("{e:#}")),
             };
+            sysexts.push(SysextStatus {
+                name: n.clone(),
+                ext_type: c.Type,
+                kind: c.Kind.clone(),
+                version: c.Version.clone(),
+                url: c.Url.clone(),
+                source: self.sources.get(n).cloned(),
+                enabled: self.enabled_image(n),
+                images: images.iter().map(ImageStatus::from).collect(),
+                error,
+            });
+        }
+        sysexts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Status {
+            sysexts,
+            errors: self.config_errors.clone(),
+        })
+    }
+
+    /// Image enabled for a sysext, if any
+    fn enabled_image(&self, name: &str) -> Option<String> {
+        self.enabled(name)
+            .into_iter()
+            .next()
+            .map(|(_, image)| image)
+    }
+
+    /// Architecture of the system
+    pub fn architecture(&self) -> &Architecture {
+        &self.system.arch
+    }
+
+    /// VERSION_ID of the system, from os-release
+    pub fn version_id(&self) -> &str {
+        &self.system.version_id
+    }
+
+    /// Configured sysexts sorted by name, with the configuration file they
+    /// have been loaded from (path on the target system)
+    pub fn configs(&self) -> Vec<(&Config, Option<&Path>)> {
+        let mut configs: Vec<(&Config, Option<&Path>)> = self
+            .configs
+            .iter()
+            .map(|(n, c)| (c, self.sources.get(n).map(PathBuf::as_path)))
+            .collect();
+        configs.sort_by(|a, b| a.0.Name.cmp(&b.0.Name));
+        configs
+    }
+
+    /// Configuration for a sysext
+    pub fn config(&self, name: &str) -> Option<&Config> {
+        self.configs.get(name)
+    }
+
+    /// Images in the store for a sysext
+    pub fn images(&self, name: &str) -> &[Image] {
+        self.images.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Image enabled for a sysext in each of the extensions directories
+    /// (paths on the target system) where there is a symlink for it, in
+    /// order of precedence
+    pub fn enabled(&self, name: &str) -> Vec<(PathBuf, String)> {
+        self.ext_type(name)
+            .extensions_dirs()
+            .iter()
+            .filter_map(|dir| {
+                let target = self
+                    .root
+                    .read_link_contents(Path::new(dir).join(format!("{name}.raw")))
+                    .ok()?;
+                let image = image_name(&target)?;
+                Some((system_path(dir), image))
+            })
+            .collect()
+    }
+
+    /// Image that `enable` would select for a sysext and release, among the
+    /// images in the store
+    pub fn latest_image(&self, name: &str, version_id: &str) -> Result<Option<Image>> {
+        let config = self
+            .configs
+            .get(name)
+            .ok_or_else(|| Error::NoConfig(name.to_string()))?;
+        self.find_latest_image(config, self.images(name), version_id)
+    }
+}
+
+/// What to do for a sysext and release when updating
+enum Update {
+    Download(Image),
+    Nothing(UpdateStatus),
+}
+
+/// Report download progress as events, checking that there is enough space
+/// before each (re)start of the download
+struct ImageTransfer<'a> {
+    manager: &'a Manager,
+    image: String,
+    position: u64,
+    total: Option<u64>,
+}
+
+impl Transfer for ImageTransfer<'_> {
+    fn start(&mut self, position: u64, total: Option<u64>) -> Result<()> {
+        match total {
+            Some(total) => self
+                .manager
+                .check_space(&self.image, total.saturating_sub(position))?,
+            None => debug!("Unknown size for {}, not checking free space", self.image),
         }
+        self.position = position;
+        self.total = total;
+        self.manager.reporter.report(&Event::DownloadStarted {
+            image: &self.image,
+            position,
+            total,
+        });
         Ok(())
     }
+
+    fn advance(&mut self, bytes: u64) {
+        self.position += bytes;
+        self.manager.reporter.report(&Event::DownloadProgress {
+            image: &self.image,
+            position: self.position,
+            total: self.total,
+        });
+    }
+
+    fn finish(&mut self, success: bool) {
+        self.manager.reporter.report(&Event::DownloadFinished {
+            image: &self.image,
+            success,
+        });
+    }
+}
+
+/// Message for a reqwest error with its causes, as "builder error" alone does
+/// not tell what is wrong
+fn causes(e: &reqwest::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        message.push_str(&format!(": {s}"));
+        source = s.source();
+    }
+    message
+}
+
+/// File name of the image a symlink points to
+fn image_name(target: &Path) -> Option<String> {
+    target.file_name().map(|f| f.to_string_lossy().into_owned())
+}
+
+/// Absolute path on the target system for a path relative to the root
+/// directory
+fn system_path(path: impl AsRef<Path>) -> PathBuf {
+    Path::new("/").join(path)
+}
+
+/// Path relative to the root directory for a path on the target system
+fn relative_path(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+/// Space available to unprivileged users on the filesystem of a directory
+fn available_space(dir: &Dir) -> io::Result<u64> {
+    let stat = fstatvfs(dir)?;
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+/// Make a downloaded image durable and atomically move it to its final name:
+/// set its mode and ownership explicitly (same owner as the store), sync it
+/// to disk, rename it and then sync the store directory so that the rename
+/// itself is durable.
+fn install(store: &Dir, file: &File, tmp: &str, dest: &str) -> Result<()> {
+    let owner = store.dir_metadata()?;
+    file.set_permissions(fs::Permissions::from_mode(IMAGE_MODE))?;
+    fchown(file, Some(owner.uid()), Some(owner.gid()))?;
+    file.sync_all()?;
+
+    debug!("Renaming: {tmp} -> {dest}");
+    store.rename(tmp, store, dest)?;
+    // Directory handles are opened with O_PATH and can not be synced
+    store.open(".")?.sync_all()?;
+    Ok(())
 }
@@ -0,0 +1,387 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::IpAddr;
+
+use log::{debug, warn};
+use reqwest::Url;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{ACCEPT, AUTHORIZATION, HeaderMap, HeaderValue, LINK, WWW_AUTHENTICATE};
+use serde::Deserialize;
+use version_compare::Version;
+
+use super::download::{self, RetryPolicy, check_status, with_retries};
+use super::error::{Error, Result};
+use super::image::Image;
+use super::progress::Transfer;
+
+/// Annotation for the VERSION_ID of the release the sysext is built for
+pub const ANNOTATION_VERSION_ID: &str = "io.github.travier.sysexts-manager.version-id";
+
+/// Annotation for the architecture of the sysext, using systemd names
+/// (`x86-64`, `arm64`)
+pub const ANNOTATION_ARCHITECTURE: &str = "io.github.travier.sysexts-manager.architecture";
+
+/// Standard annotation for the file name of a layer
+const ANNOTATION_TITLE: &str = "org.opencontainers.image.title";
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json";
+
+/// An OCI image manifest or image index. Only the fields that we need are
+/// here.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// Set for image indexes
+    #[serde(default)]
+    pub manifests: Vec<Descriptor>,
+    /// Set for image manifests
+    #[serde(default)]
+    pub layers: Vec<Descriptor>,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    pub platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagList {
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Whether the URL points to an OCI registry: `oci://<registry>/<repository>`
+pub fn is_oci(url: &str) -> bool {
+    url.starts_with("oci://")
+}
+
+/// A repository in an OCI registry. Tags are image versions and the layer
+/// digests replace the SHA256SUMS file.
+pub struct Registry<'a> {
+    client: &'a Client,
+    policy: &'a RetryPolicy,
+    /// Base URL for the registry API: http(s)://<registry>/v2/
+    api: String,
+    repository: String,
+    /// Authentication headers, if the registry asked for a token
+    headers: HeaderMap,
+}
+
+impl<'a> Registry<'a> {
+    /// Parse an `oci://` URL and authenticate to the registry if needed.
+    /// Plain HTTP is only used for registries on localhost.
+    pub fn connect(client: &'a Client, url: &str, policy: &'a RetryPolicy) -> Result<Self> {
+        let Some((host, repository)) = url
+            .strip_prefix("oci://")
+            .and_then(|u| u.trim_end_matches('/').split_once('/'))
+        else {
+            return Err(Error::Config(format!(
+                "Invalid OCI URL (expected oci://<registry>/<repository>): {url}"
+            )));
+        };
+        if repository.contains(['@', ':']) {
+            return Err(Error::Config(format!(
+                "OCI URL must not include a tag or digest, tags are used as versions: {url}"
+            )));
+        }
+        let scheme = if is_localhost(host) { "http" } else { "https" };
+        let mut registry = Registry {
+            client,
+            policy,
+            api: format!("{scheme}://{host}/v2/"),
+            repository: repository.to_string(),
+            headers: HeaderMap::new(),
+        };
+        registry.authenticate()?;
+        Ok(registry)
+    }
+
+    /// Get a token if the registry asks for one. Credentials set on the client
+    /// (Auth) are sent with the token request, otherwise the token is anonymous.
+    fn authenticate(&mut self) -> Result<()> {
+        let response = with_retries(self.policy, &self.api, || {
+            Ok(self.client.get(&self.api).send()?)
+        })?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            check_status(response)?;
+            return Ok(());
+        }
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("");
+        let Some(params) = challenge.strip_prefix("Bearer ") else {
+            return Err(Error::Remote(format!(
+                "Unsupported authentication method for registry {}: {challenge}",
+                self.api
+            )));
+        };
+        let params = parse_challenge(params);
+        let Some(realm) = params.get("realm") else {
+            return Err(Error::Remote(format!(
+                "Missing realm for registry {}",
+                self.api
+            )));
+        };
+        let mut query = vec![("scope", format!("repository:{}:pull", self.repository))];
+        if let Some(service) = params.get("service") {
+            query.push(("service", service.clone()));
+        }
+        let token_url = Url::parse_with_params(realm, &query)
+            .map_err(|e| Error::Remote(format!("Invalid realm for registry {}: {e}", self.api)))?;
+        debug!("Requesting token: {token_url}");
+        let token: Token = with_retries(self.policy, token_url.as_str(), || {
+            let response = check_status(self.client.get(token_url.clone()).send()?)?;
+            serde_json::from_slice(&response.bytes()?)
+                .map_err(|e| Error::Remote(format!("Invalid token: {token_url}: {e}")))
+        })?;
+        let Some(token) = token.token.or(token.access_token) else {
+            return Err(Error::Remote(format!(
+                "No token returned for registry {}",
+                self.api
+            )));
+        };
+        let mut value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| {
+            Error::Remote(format!("Invalid token returned for registry {}", self.api))
+        })?;
+        // Make sure that it does not show up in debug output
+        value.set_sensitive(true);
+        self.headers.insert(AUTHORIZATION, value);
+        Ok(())
+    }
+
+    fn get(&self, url: &str) -> Result<Response> {
+        with_retries(self.policy, url, || {
+            check_status(
+                self.client
+                    .get(url)
+                    .headers(self.headers.clone())
+                    .header(ACCEPT, MANIFEST_TYPES)
+                    .send()?,
+            )
+        })
+    }
+
+    /// List all tags, following pagination links
+    pub fn tags(&self) -> Result<Vec<String>> {
+        let mut tags = vec![];
+        let mut url = format!("{}{}/tags/list", self.api, self.repository);
+        loop {
+            debug!("Listing tags: {url}");
+            let response = self.get(&url)?;
+            let next = response
+                .headers()
+                .get(LINK)
+                .and_then(|h| h.to_str().ok())
+                .and_then(parse_next_link);
+            let list: TagList = serde_json::from_slice(&response.bytes()?)
+                .map_err(|e| Error::Remote(format!("Invalid tag list: {url}: {e}")))?;
+            tags.extend(list.tags);
+            match next {
+                None => return Ok(tags),
+                Some(next) => {
+                    url = Url::parse(&url)
+                        .and_then(|u| u.join(&next))
+                        .map_err(|e| Error::Remote(format!("Invalid next link: {next}: {e}")))?
+                        .to_string()
+                }
+            }
+        }
+    }
+
+    /// Get a manifest by tag or digest
+    pub fn manifest(&self, reference: &str) -> Result<Manifest> {
+        let url = format!("{}{}/manifests/{reference}", self.api, self.repository);
+        debug!("Downloading: {url}");
+        let response = self.get(&url)?;
+        serde_json::from_slice(&response.bytes()?)
+            .map_err(|e| Error::Remote(format!("Invalid manifest: {url}: {e}")))
+    }
+
+    /// List all images in the repository. Tags that are not valid versions
+    /// and manifests without the needed annotations are ignored. Manifests
+    /// that can not be fetched are logged and skipped.
+    pub fn images(&self, name: &str) -> Result<Vec<Image>> {
+        let mut images = vec![];
+        for tag in self.tags()? {
+            if Version::from(&tag).is_none() {
+                debug!("Ignoring tag that is not a version: {tag}");
+                continue;
+            }
+            let manifest = match self.manifest(&tag) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("Ignoring tag {tag}: {e}");
+                    continue;
+                }
+            };
+            if manifest.manifests.is_empty() {
+                images.extend(self.image(name, &tag, &manifest, None));
+                continue;
+            }
+            // Image index with one manifest per architecture and/or release
+            for d in &manifest.manifests {
+                let m = match self.manifest(&d.digest) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("Ignoring manifest {} for tag {tag}: {e}", d.digest);
+                        continue;
+                    }
+                };
+                let mut annotations = d.annotations.clone();
+                annotations.extend(m.annotations.clone());
+                let m = Manifest { annotations, ..m };
+                images.extend(self.image(name, &tag, &m, d.platform.as_ref()));
+            }
+        }
+        Ok(images)
+    }
+
+    /// Turn an image manifest into an image, using the layer digest as hash
+    fn image(
+        &self,
+        name: &str,
+        tag: &str,
+        manifest: &Manifest,
+        platform: Option<&Platform>,
+    ) -> Option<Image> {
+        let layer = match manifest.layers.as_slice() {
+            [layer] => layer,
+            layers => layers.iter().find(|l| {
+                l.annotations
+                    .get(ANNOTATION_TITLE)
+                    .is_some_and(|t| t.ends_with(".raw"))
+            })?,
+        };
+        let hash = layer.digest.strip_prefix("sha256:")?;
+        let annotation = |key: &str| manifest.annotations.get(key).or(layer.annotations.get(key));
+        let Some(version_id) = annotation(ANNOTATION_VERSION_ID) else {
+            debug!("Ignoring tag without VERSION_ID annotation: {tag}");
+            return None;
+        };
+        let arch = match annotation(ANNOTATION_ARCHITECTURE) {
+            Some(a) => a.as_str(),
+            None => match platform.map(|p| p.architecture.as_str()) {
+                Some("amd64") => "x86-64",
+                Some("arm64") => "arm64",
+                _ => {
+                    debug!("Ignoring tag without architecture annotation: {tag}");
+                    return None;
+                }
+            },
+        };
+        let filename = format!("{name}-{tag}-{version_id}-{arch}.raw");
+        match Image::new(name, filename.clone().into(), Some(hash.to_string())) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                debug!("Ignoring tag {tag}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Size of the layer for an image
+    pub fn size(&self, image: &Image) -> Result<Option<u64>> {
+        let url = self.blob_url(image)?;
+        download::size(self.client, &url, &self.headers, self.policy)
+    }
+
+    fn blob_url(&self, image: &Image) -> Result<String> {
+        let Some(hash) = &image.hash else {
+            return Err(Error::InvalidImage(format!(
+                "Missing digest for image: {}",
+                image.path()
+            )));
+        };
+        Ok(format!(
+            "{}{}/blobs/sha256:{hash}",
+            self.api, self.repository
+        ))
+    }
+
+    /// Download the layer for an image to the given file and return its
+    /// sha256sum. Resumes the download if the file is not empty.
+    pub fn download(
+        &self,
+        image: &Image,
+        file: &mut File,
+        transfer: &mut dyn Transfer,
+    ) -> Result<String> {
+        let url = self.blob_url(image)?;
+        debug!("Downloading: {url}");
+        download::download(
+            self.client,
+            &url,
+            &self.headers,
+            file,
+            self.policy,
+            transfer,
+        )
+    }
+}
+
+fn is_localhost(host: &str) -> bool {
+    let host = match host.rsplit_once(':') {
+        Some((h, port)) if port.chars().all(|c| c.is_ascii_digit()) => h,
+        _ => host,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Parse the parameters of a WWW-Authenticate challenge:
+/// `realm="...",service="...",scope="..."`
+fn parse_challenge(params: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let (value, next) = match value.strip_prefix('"') {
+            Some(v) => v.split_once('"').unwrap_or((v, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        map.insert(key, value.to_string());
+        rest = next;
+    }
+    map
+}
+
+/// Get the target of a `Link: <url>; rel="next"` header
+fn parse_next_link(link: &str) -> Option<String> {
+    let (target, params) = link.split_once(';')?;
+    if !params.contains("rel=\"next\"") {
+        return None;
+    }
+    Some(
+        target
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string(),
+    )
+}
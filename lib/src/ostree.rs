@@ -2,12 +2,13 @@
 // SPDX-License-Identifier: MIT
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process::Command;
 
 use serde::Deserialize;
 
-use anyhow::Context;
-use anyhow::Result;
+use super::deployment::{self, Backend};
+use super::error::{Error, Result};
 
 /// Representation of the rpm-ostree client-side state; this
 /// can be parsed directly from the output of `rpm-ostree status --json`.
@@ -38,10 +39,47 @@ pub struct Deployment {
     pub version: Option<String>,
 }
 
-#[allow(dead_code)]
-pub fn rpm_ostree_status() -> Result<()> {
+impl Status {
+    /// Parse the output of `rpm-ostree status --json`
+    pub fn from_json(json: &[u8]) -> Result<Status> {
+        serde_json::from_slice(json)
+            .map_err(|e| Error::Command(format!("failed to parse 'rpm-ostree status' output: {e}")))
+    }
+
+    /// Convert the rpm-ostree deployments into generic deployments
+    pub fn to_deployments(&self) -> Vec<deployment::Deployment> {
+        self.deployments
+            .iter()
+            .map(|d| {
+                let staged = d.staged.unwrap_or(false);
+                deployment::Deployment {
+                    booted: d.booted,
+                    staged,
+                    rollback: !d.booted && !staged,
+                    pinned: d.pinned,
+                    image: d.container_image_reference.clone(),
+                    version: d.version.clone(),
+                    path: d.path(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Deployment {
+    /// Path to the deployment checkout, relative to the root directory
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "ostree/deploy/{}/deploy/{}.{}",
+            self.osname, self.checksum, self.serial
+        ))
+    }
+}
+
+pub fn rpm_ostree_status() -> Result<Status> {
     let mut cmd = Command::new("rpm-ostree");
-    cmd.env("RPMOSTREE_CLIENT_ID", "manager");
+    cmd.env("RPMOSTREE_CLIENT_ID", "manager")
+        .args(["status", "--json"]);
 
     // Retry on temporary activation failures, see
     // https://github.com/coreos/rpm-ostree/issues/2531
@@ -50,7 +88,7 @@ pub fn rpm_ostree_status() -> Result<()> {
     let mut retries = 0;
     let cmd_res = loop {
         retries += 1;
-        let res = cmd.args(["status", "--json"]).output()?;
+        let res = cmd.output()?;
 
         if res.status.success() || retries >= max_retries {
             break res;
@@ -59,20 +97,25 @@ pub fn rpm_ostree_status() -> Result<()> {
     };
 
     if !cmd_res.status.success() {
-        return Err(anyhow::Error::msg(format!(
+        return Err(Error::Command(format!(
             "running 'rpm-ostree status' failed: {}",
             String::from_utf8_lossy(&cmd_res.stderr)
         )));
     }
 
     // https://github.com/coreos/rpm-ostree/blob/main/rust/rpmostree-client/src/lib.rs
-    let status: Status = serde_json::from_slice(&cmd_res.stdout)
-        .context("failed to parse 'rpm-ostree status' output")?;
+    Status::from_json(&cmd_res.stdout)
+}
+
+/// rpm-ostree deployment backend
+pub struct RpmOstree {}
 
-    for d in status.deployments {
-        println!("Deployments: {}", d.version.clone().unwrap());
-        // https://docs.rs/os-release/0.1.0/os_release/
+impl Backend for RpmOstree {
+    fn name(&self) -> &'static str {
+        "rpm-ostree"
     }
 
-    Ok(())
+    fn deployments(&self) -> Result<Vec<deployment::Deployment>> {
+        Ok(rpm_ostree_status()?.to_deployments())
+    }
 }
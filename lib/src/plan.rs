@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{self, Error, Result};
+
+/// Version of the plan file format
+pub const PLAN_VERSION: u32 = 1;
+
+/// A single change to the system. Paths are absolute paths on the target
+/// system, without the root directory prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Change {
+    /// Download an image to the store. The hash is verified once downloaded.
+    Download {
+        name: String,
+        image: String,
+        hash: String,
+        /// Size in bytes, if the server told us
+        size: Option<u64>,
+    },
+    /// Create a symlink to enable an image, replacing the `previous` one if
+    /// any
+    Link {
+        name: String,
+        path: PathBuf,
+        target: PathBuf,
+        previous: Option<PathBuf>,
+    },
+    /// Remove a symlink to disable an image
+    Unlink {
+        name: String,
+        path: PathBuf,
+        target: Option<PathBuf>,
+    },
+    /// Delete an image, a partial download or a configuration file
+    Delete { path: PathBuf, size: u64 },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Download {
+                image, size: None, ..
+            } => write!(f, "Download {image} (unknown size)"),
+            Change::Download {
+                image,
+                size: Some(size),
+                ..
+            } => write!(f, "Download {image} ({size} bytes)"),
+            Change::Link {
+                path,
+                target,
+                previous: None,
+                ..
+            } => write!(
+                f,
+                "Create symlink {} -> {}",
+                path.display(),
+                target.display()
+            ),
+            Change::Link {
+                path,
+                target,
+                previous: Some(previous),
+                ..
+            } => write!(
+                f,
+                "Retarget symlink {}: {} -> {}",
+                path.display(),
+                previous.display(),
+                target.display()
+            ),
+            Change::Unlink { path, .. } => write!(f, "Remove symlink {}", path.display()),
+            Change::Delete { path, size } => {
+                write!(f, "Delete {} ({size} bytes)", path.display())
+            }
+        }
+    }
+}
+
+/// Changes computed by a dry run, in the order in which they are applied.
+/// Plans can be saved to a file to be reviewed and applied later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    pub changes: Vec<Change>,
+}
+
+impl Default for Plan {
+    fn default() -> Self {
+        Plan::new(vec![])
+    }
+}
+
+impl Plan {
+    pub fn new(changes: Vec<Change>) -> Plan {
+        Plan {
+            version: PLAN_VERSION,
+            changes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn load(path: &Path) -> Result<Plan> {
+        let content = fs::read(path).map_err(error::io(path))?;
+        let plan: Plan = serde_json::from_slice(&content)
+            .map_err(|e| Error::Plan(format!("Invalid plan: {}: {e}", path.display())))?;
+        if plan.version != PLAN_VERSION {
+            return Err(Error::Plan(format!(
+                "Unsupported plan version {} (expected {PLAN_VERSION}): {}",
+                plan.version,
+                path.display()
+            )));
+        }
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut content = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Plan(format!("Could not serialize plan: {e}")))?;
+        content.push('\n');
+        fs::write(path, content).map_err(error::io(path))
+    }
+}
@@ -0,0 +1,55 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::io::{Result as IoResult, Write};
+
+use super::error::Result;
+
+/// Progress of a single download. The manager forwards it to its Reporter.
+pub trait Transfer: Send {
+    /// The download (re)starts at `position` bytes (non zero when resuming a
+    /// partial download). The total size is known if the server sent it.
+    /// Returning an error aborts the download.
+    fn start(&mut self, position: u64, total: Option<u64>) -> Result<()>;
+
+    /// Some bytes have been downloaded
+    fn advance(&mut self, bytes: u64);
+
+    /// The download is done, successfully or not
+    fn finish(&mut self, success: bool);
+}
+
+/// Ignore all progress updates
+pub struct NoProgress;
+
+impl Transfer for NoProgress {
+    fn start(&mut self, _position: u64, _total: Option<u64>) -> Result<()> {
+        Ok(())
+    }
+    fn advance(&mut self, _bytes: u64) {}
+    fn finish(&mut self, _success: bool) {}
+}
+
+/// Report the bytes written through this writer as progress for a download
+pub struct ProgressWriter<'a, W> {
+    writer: W,
+    transfer: &'a mut dyn Transfer,
+}
+
+impl<'a, W> ProgressWriter<'a, W> {
+    pub fn new(writer: W, transfer: &'a mut dyn Transfer) -> Self {
+        ProgressWriter { writer, transfer }
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let n = self.writer.write(buf)?;
+        self.transfer.advance(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+//! Results of the manager operations. They are serialized as is for the
+//! machine-readable output of the CLI: fields may be added but existing
+//! fields are not renamed or removed.
+
+use std::path::PathBuf;
+
+use serde::{Serialize, Serializer};
+
+use super::config::ExtensionType;
+use super::error::Error;
+use super::image::Image;
+
+/// State of all configured sysexts
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub sysexts: Vec<SysextStatus>,
+    /// Configuration files that could not be loaded
+    pub errors: Vec<ConfigError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SysextStatus {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ext_type: ExtensionType,
+    pub kind: String,
+    /// Pinned version, for the "version" Kind
+    pub version: Option<String>,
+    pub url: String,
+    /// Configuration file
+    pub source: Option<PathBuf>,
+    /// Image currently enabled
+    pub enabled: Option<String>,
+    /// Images in the store
+    pub images: Vec<ImageStatus>,
+    /// Why no image can be enabled for the current release, if so
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageStatus {
+    pub image: String,
+    pub version: String,
+    pub version_id: String,
+    pub architecture: String,
+}
+
+impl From<&Image> for ImageStatus {
+    fn from(image: &Image) -> Self {
+        ImageStatus {
+            image: image.path(),
+            version: image.version.clone(),
+            version_id: image.version_id.clone(),
+            architecture: image.architecture.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigError {
+    pub source: PathBuf,
+    pub error: String,
+}
+
+/// Result of the update of a sysext for a release
+#[derive(Debug, Serialize)]
+pub struct Updated {
+    pub name: String,
+    pub version_id: String,
+    pub status: UpdateStatus,
+    /// Image downloaded, or that would be downloaded but failed
+    pub image: Option<String>,
+    /// Why the update failed, serialized as the error message
+    #[serde(serialize_with = "error_message")]
+    pub error: Option<Error>,
+}
+
+fn error_message<S: Serializer>(error: &Option<Error>, serializer: S) -> Result<S::Ok, S::Error> {
+    error
+        .as_ref()
+        .map(|e| format!("{e:#}"))
+        .serialize(serializer)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateStatus {
+    /// A new image has been downloaded
+    Updated,
+    /// The latest image is already in the store
+    UpToDate,
+    /// Nothing to download or nothing that we can download: no remote image
+    /// for this release or local image not matching the published hash
+    /// without repair
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Enabled {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ext_type: ExtensionType,
+    pub image: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Disabled {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ext_type: ExtensionType,
+    /// Image that was enabled
+    pub image: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Added {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ext_type: ExtensionType,
+    pub url: String,
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Removed {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ext_type: ExtensionType,
+    /// Images and configuration files removed
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Cleaned {
+    /// Images and partial downloads removed
+    pub files: Vec<PathBuf>,
+    /// Number of images removed
+    pub images: usize,
+    /// Bytes freed
+    pub reclaimed: u64,
+}
@@ -0,0 +1,52 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+//! Events emitted by the manager while it works, so that callers can report
+//! what is happening as it happens: the library never prints anything.
+
+use super::error::Error;
+use super::report::{Disabled, Enabled};
+
+/// Something that happened. New variants may be added.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// An image has been enabled, or was already enabled
+    Enabled(&'a Enabled),
+    /// An image has been disabled
+    Disabled(&'a Disabled),
+    /// A download for an image (re)starts at `position` bytes (non zero when
+    /// resuming a partial download). The total size is known if the server
+    /// sent it.
+    DownloadStarted {
+        image: &'a str,
+        position: u64,
+        total: Option<u64>,
+    },
+    /// Some bytes have been downloaded, `position` bytes in total
+    DownloadProgress {
+        image: &'a str,
+        position: u64,
+        total: Option<u64>,
+    },
+    /// The download is done, successfully or not
+    DownloadFinished { image: &'a str, success: bool },
+    /// An image does not match its published hash or the signature of the
+    /// SHA256SUMS file for a sysext is invalid. `image` is the image file
+    /// name, or the sysext name for signatures.
+    VerificationFailed { image: &'a str, error: &'a Error },
+}
+
+/// Receives the events from the manager. Downloads run in parallel so
+/// implementations must be thread safe and are expected to aggregate the
+/// progress of all downloads if needed.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: &Event);
+}
+
+/// Ignore all events
+pub struct NoReporter;
+
+impl Reporter for NoReporter {
+    fn report(&self, _event: &Event) {}
+}
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: MIT
 
 use sha2::{Digest, Sha256};
-use std::io::{Result as IoResult, Write};
+use std::io::{self, Read, Result as IoResult, Write};
 
 // From https://users.rust-lang.org/t/read-and-hash-sha1-at-the-same-time/54458
 pub struct Sha256Writer<W> {
@@ -21,15 +21,40 @@ impl<W> Sha256Writer<W> {
     pub fn digest(self) -> String {
         hex::encode(self.hasher.finalize())
     }
+
+    /// Only hash the content from the reader, without writing it. Used to
+    /// resume hashing from existing content.
+    pub fn seed<R: Read>(&mut self, reader: &mut R) -> IoResult<u64> {
+        let mut buf = [0u8; 64 * 1024];
+        let mut total = 0;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(total);
+            }
+            self.hasher.update(&buf[..n]);
+            total += n as u64;
+        }
+    }
 }
 
 impl<W: Write> Write for Sha256Writer<W> {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        self.hasher.update(buf);
-        self.writer.write(buf)
+        // Only hash what has actually been written, as writes may be short
+        let n = self.writer.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
     }
 
     fn flush(&mut self) -> IoResult<()> {
         self.writer.flush()
     }
 }
+
+/// Compute the sha256sum of the content of a reader, for example an already
+/// opened file, streaming its content
+pub fn sha256sum<R: Read>(reader: &mut R) -> IoResult<String> {
+    let mut writer = Sha256Writer::new(io::sink());
+    io::copy(reader, &mut writer)?;
+    Ok(writer.digest())
+}
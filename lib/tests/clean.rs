@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use common::{copy_root, list_images, manager};
+
+fn clean(root: &Path, keep: usize) {
+    let mut manager = manager(root);
+    manager.clean(keep).unwrap();
+}
+
+#[test]
+fn clean_keep_newest() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    clean(root, 1);
+    assert_eq!(
+        list_images(root),
+        vec![
+            "bar-20251120-43-x86-64.raw",
+            "duck-1.6.5-43-x86-64.raw",
+            "foo-3-43-x86-64.raw",
+        ]
+    );
+}
+
+#[test]
+fn clean_keep_enabled_and_pinned() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_pinned"));
+    let root = tmp.path();
+    symlink(
+        "../../var/lib/extensions.d/duck-1.6.3-43-x86-64.raw",
+        root.join("run/extensions/duck.raw"),
+    )
+    .unwrap();
+    clean(root, 1);
+    assert_eq!(
+        list_images(root),
+        vec![
+            "bar-20251109-43-x86-64.raw",
+            "bar-20251120-43-x86-64.raw",
+            "duck-1.6.3-43-x86-64.raw",
+            "duck-1.6.5-43-x86-64.raw",
+            "foo-2-43-x86-64.raw",
+            "foo-3-43-x86-64.raw",
+        ]
+    );
+}
+
+#[test]
+fn clean_other_arch() {
+    let tmp = copy_root(Path::new("./test-data/valid_current_arch"));
+    let root = tmp.path();
+    clean(root, 2);
+    assert_eq!(
+        list_images(root),
+        vec![
+            "bar-20251120-43-x86-64.raw",
+            "duck-1.6.5-43-x86-64.raw",
+            "foo-3-43-x86-64.raw",
+        ]
+    );
+}
@@ -0,0 +1,238 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+//! Helpers shared by integration tests
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use cap_std::ambient_authority;
+use cap_std::fs::Dir;
+use sysexts_manager_lib::manager::Manager;
+use sysexts_manager_lib::reporter::{Event, Reporter};
+use tempfile::TempDir;
+
+/// Copy a test root to a temporary directory for tests that modify it
+pub fn copy_root(src: &Path) -> TempDir {
+    fn copy_dir(src: &Path, dst: &Path) {
+        fs::create_dir_all(dst).unwrap();
+        for entry in fs::read_dir(src).unwrap() {
+            let entry = entry.unwrap();
+            let dst = dst.join(entry.file_name());
+            let file_type = entry.file_type().unwrap();
+            // Skip symlinks that may be concurrently created by other tests
+            if file_type.is_dir() {
+                copy_dir(&entry.path(), &dst);
+            } else if file_type.is_file() {
+                fs::copy(entry.path(), dst).unwrap();
+            }
+        }
+    }
+    let tmp = TempDir::new().unwrap();
+    copy_dir(src, tmp.path());
+    tmp
+}
+
+/// Open a test root, as the manager does
+pub fn open_root(root: &Path) -> Dir {
+    Dir::open_ambient_dir(root, ambient_authority()).unwrap()
+}
+
+/// Manager for a test root, with the configuration and the images loaded
+pub fn manager(root: &Path) -> Manager {
+    let mut manager = sysexts_manager_lib::manager::new_with_root(root).unwrap();
+    manager.load_config().unwrap();
+    manager.load_images().unwrap();
+    manager
+}
+
+/// Sorted file names of the images in the store of a test root, without
+/// partial downloads
+pub fn list_images(root: &Path) -> Vec<String> {
+    let mut images: Vec<String> = fs::read_dir(root.join("var/lib/extensions.d"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .filter(|f| !f.ends_with(".tmp"))
+        .collect();
+    images.sort();
+    images
+}
+
+fn gpg(home: &Path, args: &[&str]) {
+    let res = Command::new("gpg")
+        .arg("--homedir")
+        .arg(home)
+        .args(["--batch", "--passphrase", ""])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        res.status.success(),
+        "{}",
+        String::from_utf8_lossy(&res.stderr)
+    );
+}
+
+/// Generate a signing key, export it to a keyring and sign the data
+pub fn sign(tmp: &Path, name: &str, data: &Path) -> Vec<u8> {
+    let home = tmp.join(format!("home-{name}"));
+    fs::create_dir(&home).unwrap();
+    gpg(
+        &home,
+        &["--quick-gen-key", name, "ed25519", "sign", "never"],
+    );
+    let keyring = tmp.join(format!("{name}.gpg"));
+    gpg(
+        &home,
+        &["--output", keyring.to_str().unwrap(), "--export", name],
+    );
+    let signature = tmp.join(format!("{name}.sig"));
+    gpg(
+        &home,
+        &[
+            "--output",
+            signature.to_str().unwrap(),
+            "--detach-sign",
+            data.to_str().unwrap(),
+        ],
+    );
+    // Stop the agent started for this home directory
+    let _ = Command::new("gpgconf")
+        .arg("--homedir")
+        .arg(&home)
+        .args(["--kill", "all"])
+        .status();
+    fs::read(signature).unwrap()
+}
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercase
+    pub headers: HashMap<String, String>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Announce a different length than the body to simulate an interrupted
+    /// transfer
+    pub content_length: Option<usize>,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status: 200,
+            headers: vec![],
+            body: body.into(),
+            content_length: None,
+        }
+    }
+
+    pub fn status(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+            content_length: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// Start a minimal HTTP server on localhost calling the handler for each
+/// request. Returns the base URL for the server.
+pub fn serve<F>(handler: F) -> String
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut split = line.split_whitespace();
+                let method = split.next().unwrap_or("").to_string();
+                let path = split.next().unwrap_or("").to_string();
+                let mut headers = HashMap::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some((k, v)) = line.trim_end().split_once(':') {
+                        headers.insert(k.trim().to_lowercase(), v.trim().to_string());
+                    }
+                }
+                let response = handler(&Request {
+                    method,
+                    path,
+                    headers,
+                });
+                let mut head = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.content_length.unwrap_or(response.body.len())
+                );
+                for (k, v) in &response.headers {
+                    head.push_str(&format!("{k}: {v}\r\n"));
+                }
+                head.push_str("\r\n");
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&response.body);
+            });
+        }
+    });
+    format!("http://{addr}")
+}
+
+/// Record the events from a manager, except download progress
+#[derive(Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Recorder {
+    pub fn events(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Reporter for Recorder {
+    fn report(&self, event: &Event) {
+        let event = match event {
+            Event::Enabled(e) => format!("enabled {} {}", e.name, e.image),
+            Event::Disabled(d) => format!("disabled {}", d.name),
+            Event::DownloadStarted {
+                image, position, ..
+            } => format!("download-started {image} {position}"),
+            Event::DownloadProgress { .. } => return,
+            Event::DownloadFinished { image, success } => {
+                format!("download-finished {image} {success}")
+            }
+            Event::VerificationFailed { image, .. } => format!("verification-failed {image}"),
+            e => format!("{e:?}"),
+        };
+        self.0.lock().unwrap().push(event);
+    }
+}
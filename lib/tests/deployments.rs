@@ -0,0 +1,52 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::open_root;
+use sysexts_manager_lib::bootc::Host;
+use sysexts_manager_lib::deployment::Deployment;
+use sysexts_manager_lib::ostree::Status;
+
+fn version_ids(root: &Path, deployments: &[Deployment]) -> (String, String) {
+    let staged = deployments.iter().find(|d| d.staged).unwrap();
+    let booted = deployments.iter().find(|d| d.booted).unwrap();
+    let dir = open_root(root);
+    (
+        staged.version_id(&dir, root).unwrap(),
+        booted.version_id(&dir, root).unwrap(),
+    )
+}
+
+#[test]
+fn rpm_ostree_staged() {
+    let root = Path::new("./test-data/ostree_staged");
+    let json = fs::read(root.join("rpm-ostree-status.json")).unwrap();
+    let deployments = Status::from_json(&json).unwrap().to_deployments();
+    assert_eq!(deployments.len(), 2);
+    assert_eq!(
+        deployments.iter().find(|d| d.staged).unwrap().version,
+        Some("44.20251015.0".into())
+    );
+    assert_eq!(version_ids(root, &deployments), ("44".into(), "43".into()));
+}
+
+#[test]
+fn bootc_staged() {
+    let root = Path::new("./test-data/bootc_staged");
+    let json = fs::read(root.join("bootc-status.json")).unwrap();
+    let deployments = Host::from_json(&json).unwrap().to_deployments();
+    assert_eq!(deployments.len(), 3);
+    assert_eq!(
+        deployments.iter().find(|d| d.staged).unwrap().image,
+        Some("quay.io/fedora/fedora-bootc:44".into())
+    );
+    assert_eq!(version_ids(root, &deployments), ("44".into(), "43".into()));
+
+    let rollback = deployments.iter().find(|d| d.rollback).unwrap();
+    assert!(rollback.pinned);
+    assert_eq!(rollback.version_id(&open_root(root), root).unwrap(), "42");
+}
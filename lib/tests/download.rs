@@ -0,0 +1,230 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{Request, Response, serve};
+use reqwest::blocking::Client;
+use reqwest::header::HeaderMap;
+use sysexts_manager_lib::download::{self, RetryPolicy, get_text};
+use sysexts_manager_lib::error::Result;
+use sysexts_manager_lib::progress::{NoProgress, Transfer};
+use sysexts_manager_lib::sha256writer::sha256sum;
+use tempfile::TempDir;
+
+fn client() -> Client {
+    Client::builder().no_proxy().build().unwrap()
+}
+
+fn policy(retries: u32) -> RetryPolicy {
+    RetryPolicy {
+        retries,
+        delay: Duration::from_millis(10),
+    }
+}
+
+/// Download to a file that may hold a partial download, as the manager does
+fn download(url: &str, path: &Path, retries: u32, transfer: &mut dyn Transfer) -> Result<String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .unwrap();
+    download::download(
+        &client(),
+        url,
+        &HeaderMap::new(),
+        &mut file,
+        &policy(retries),
+        transfer,
+    )
+}
+
+fn content() -> Vec<u8> {
+    (0..100_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+/// Serve the content, supporting Range requests if asked to
+fn serve_content(ranges: bool, requests: Arc<Mutex<Vec<Option<String>>>>) -> String {
+    serve(move |r: &Request| {
+        let content = content();
+        let range = r.headers.get("range").cloned();
+        requests.lock().unwrap().push(range.clone());
+        match range.and_then(|r| r.strip_prefix("bytes=").map(|r| r.to_string())) {
+            Some(r) if ranges => {
+                let start: usize = r.trim_end_matches('-').parse().unwrap();
+                let total = content.len();
+                if start >= total {
+                    return Response::status(416)
+                        .header("Content-Range", &format!("bytes */{total}"));
+                }
+                Response {
+                    status: 206,
+                    body: content[start..].to_vec(),
+                    ..Response::ok(vec![])
+                }
+                .header(
+                    "Content-Range",
+                    &format!("bytes {start}-{}/{total}", total - 1),
+                )
+            }
+            _ => Response::ok(content),
+        }
+    })
+}
+
+fn partial_download(ranges: bool, partial: usize) -> Vec<Option<String>> {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let url = format!(
+        "{}/foo/foo-3-43-x86-64.raw",
+        serve_content(ranges, requests.clone())
+    );
+
+    let tmp = TempDir::new().unwrap();
+    let path = tmp.path().join("foo-3-43-x86-64.raw.tmp");
+    fs::write(&path, &content()[..partial]).unwrap();
+
+    let digest = download(&url, &path, 0, &mut NoProgress).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), content());
+    assert_eq!(
+        digest,
+        sha256sum(&mut fs::File::open(&path).unwrap()).unwrap()
+    );
+
+    requests.lock().unwrap().clone()
+}
+
+#[test]
+fn resume_download() {
+    assert_eq!(
+        partial_download(true, 40_000),
+        vec![Some("bytes=40000-".into())]
+    );
+}
+
+#[test]
+fn resume_download_no_range_support() {
+    assert_eq!(
+        partial_download(false, 40_000),
+        vec![Some("bytes=40000-".into())]
+    );
+}
+
+#[test]
+fn resume_complete_download() {
+    // The server answers with 416 for the range starting at the end of the
+    // file, which must not trigger a new full download
+    assert_eq!(
+        partial_download(true, 100_000),
+        vec![Some("bytes=100000-".into())]
+    );
+}
+
+#[test]
+fn retry_after() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let url = serve(move |_| match counter.fetch_add(1, Ordering::SeqCst) {
+        0 => Response::status(503).header("Retry-After", "0"),
+        1 => Response::status(429),
+        _ => Response::ok("SHA256SUMS content"),
+    });
+    let text = get_text(&client(), &format!("{url}/foo/SHA256SUMS"), &policy(2)).unwrap();
+    assert_eq!(text, "SHA256SUMS content");
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn no_retry_on_not_found() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let url = serve(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Response::status(404)
+    });
+    assert!(get_text(&client(), &format!("{url}/foo/SHA256SUMS"), &policy(2)).is_err());
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn retry_interrupted_download() {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let seen = requests.clone();
+    let url = serve(move |r: &Request| {
+        let content = content();
+        let range = r.headers.get("range").cloned();
+        seen.lock().unwrap().push(range.clone());
+        match range {
+            // Close the connection half way through the transfer
+            None => Response {
+                content_length: Some(content.len()),
+                ..Response::ok(&content[..50_000])
+            },
+            Some(_) => Response {
+                status: 206,
+                ..Response::ok(&content[50_000..])
+            }
+            .header(
+                "Content-Range",
+                &format!("bytes 50000-{}/{}", content.len() - 1, content.len()),
+            ),
+        }
+    });
+
+    let tmp = TempDir::new().unwrap();
+    let path = tmp.path().join("foo-3-43-x86-64.raw.tmp");
+    let digest = download(&format!("{url}/foo.raw"), &path, 1, &mut NoProgress).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), content());
+    assert_eq!(
+        digest,
+        sha256sum(&mut fs::File::open(&path).unwrap()).unwrap()
+    );
+    assert_eq!(
+        requests.lock().unwrap().clone(),
+        vec![None, Some("bytes=50000-".into())]
+    );
+}
+
+/// Record the progress updates for a download
+#[derive(Default)]
+struct Recorder {
+    starts: Vec<(u64, Option<u64>)>,
+    bytes: u64,
+}
+
+impl Transfer for Recorder {
+    fn start(&mut self, position: u64, total: Option<u64>) -> Result<()> {
+        self.starts.push((position, total));
+        self.bytes = position;
+        Ok(())
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    fn finish(&mut self, _success: bool) {}
+}
+
+#[test]
+fn resume_download_progress() {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let url = format!("{}/foo.raw", serve_content(true, requests));
+    let tmp = TempDir::new().unwrap();
+    let path = tmp.path().join("foo-3-43-x86-64.raw.tmp");
+    fs::write(&path, &content()[..40_000]).unwrap();
+
+    let mut recorder = Recorder::default();
+    download(&url, &path, 0, &mut recorder).unwrap();
+    let total = content().len() as u64;
+    assert_eq!(recorder.starts, vec![(40_000, Some(total))]);
+    assert_eq!(recorder.bytes, total);
+}
@@ -0,0 +1,55 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::path::Path;
+
+use common::{copy_root, manager, open_root};
+use sysexts_manager_lib::config::{Config, ExtensionType};
+use sysexts_manager_lib::error::Error;
+use sysexts_manager_lib::lock::{Lock, Wait};
+
+#[test]
+fn no_config() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let manager = manager(tmp.path());
+    let err = manager.enable(&"unknown".to_string()).unwrap_err();
+    assert!(
+        matches!(&err, Error::NoConfig(name) if name == "unknown"),
+        "{err}"
+    );
+}
+
+#[test]
+fn already_enabled() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let mut manager = manager(root);
+    manager.enable(&"foo".to_string()).unwrap();
+    let err = manager.remove_sysext("foo").unwrap_err();
+    assert!(
+        matches!(&err, Error::Enabled { name, ext_type: ExtensionType::Sysext } if name == "foo"),
+        "{err}"
+    );
+    assert!(root.join("etc/sysexts-manager/foo.conf").exists());
+}
+
+#[test]
+fn locked() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let _lock = Lock::acquire_in(&open_root(root), root, Wait::No).unwrap();
+    let err = manager(root).enable_all().unwrap_err();
+    assert!(matches!(err, Error::Locked(_)), "{err}");
+}
+
+#[test]
+fn version_with_latest_kind() {
+    let config: Config = toml::from_str(
+        "Name = \"foo\"\nKind = \"latest\"\nUrl = \"https://example.com\"\nVersion = \"1\"\n",
+    )
+    .unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(matches!(err, Error::Config(_)), "{err}");
+}
@@ -0,0 +1,33 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs;
+
+use common::sign;
+use sysexts_manager_lib::gpg;
+use tempfile::TempDir;
+
+const SHA256SUMS: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  foo-3-43-x86-64.raw\n";
+
+#[test]
+fn verify_signature() {
+    let tmp = TempDir::new().unwrap();
+    let data = tmp.path().join("SHA256SUMS");
+    fs::write(&data, SHA256SUMS).unwrap();
+
+    let signature = sign(tmp.path(), "trusted", &data);
+    let other_signature = sign(tmp.path(), "other", &data);
+    let keyring = fs::read(tmp.path().join("trusted.gpg")).unwrap();
+
+    gpg::verify(&keyring, SHA256SUMS.as_bytes(), &signature).unwrap();
+
+    // Tampered data
+    let tampered = SHA256SUMS.replace("foo-3", "foo-4");
+    assert!(gpg::verify(&keyring, tampered.as_bytes(), &signature).is_err());
+
+    // Signed with a key that is not in the keyring
+    assert!(gpg::verify(&keyring, SHA256SUMS.as_bytes(), &other_signature).is_err());
+}
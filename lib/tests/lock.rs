@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use common::{copy_root, manager, open_root};
+use sysexts_manager_lib::lock::{Lock, Wait};
+use sysexts_manager_lib::manager::Manager;
+
+fn waiting(root: &Path, wait: Wait) -> Manager {
+    let mut manager = manager(root);
+    manager.set_wait(wait);
+    manager
+}
+
+#[test]
+fn locked_by_other_instance() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let lock = Lock::acquire_in(&open_root(root), root, Wait::No).unwrap();
+
+    let err = waiting(root, Wait::No).enable_all().unwrap_err();
+    assert!(
+        err.to_string()
+            .contains(&format!("PID {}", std::process::id())),
+        "{err}"
+    );
+    assert!(!root.join("run/extensions/foo.raw").exists());
+
+    drop(lock);
+    assert!(!root.join("run/sysexts-manager/lock").exists());
+    waiting(root, Wait::No).enable_all().unwrap();
+    assert!(root.join("run/extensions/foo.raw").exists());
+    assert!(!root.join("run/sysexts-manager/lock").exists());
+}
+
+#[test]
+fn wait_for_lock() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+
+    let lock = Lock::acquire_in(&open_root(root), root, Wait::No).unwrap();
+    let start = Instant::now();
+    let err = waiting(root, Wait::Timeout(Duration::from_millis(300)))
+        .enable_all()
+        .unwrap_err();
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert!(err.to_string().contains("Timed out"), "{err}");
+
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        drop(lock);
+    });
+    waiting(root, Wait::Forever).enable_all().unwrap();
+    release.join().unwrap();
+    assert!(root.join("run/extensions/foo.raw").exists());
+}
@@ -0,0 +1,41 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use sysexts_manager_lib::ostree::rpm_ostree_status;
+use tempfile::TempDir;
+
+#[test]
+fn rpm_ostree_status_retry() {
+    // Fake rpm-ostree that fails on the first call, as on temporary
+    // activation failures, and checks the arguments on each call
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path();
+    let status = Path::new("./test-data/ostree_staged/rpm-ostree-status.json")
+        .canonicalize()
+        .unwrap();
+    let script = dir.join("rpm-ostree");
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\n\
+             [ \"$*\" = \"status --json\" ] || {{ echo \"unexpected arguments: $*\" >&2; exit 2; }}\n\
+             [ -e {dir}/called ] || {{ touch {dir}/called; exit 1; }}\n\
+             cat {status}\n",
+            dir = dir.display(),
+            status = status.display(),
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!("{}:{}", dir.display(), env::var("PATH").unwrap_or_default());
+    // Only test in this file, so no other thread is reading the environment
+    unsafe { env::set_var("PATH", path) };
+
+    let status = rpm_ostree_status().unwrap();
+    assert_eq!(status.deployments.len(), 2);
+}
@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use common::{copy_root, list_images, manager};
+use sysexts_manager_lib::error::Error;
+use sysexts_manager_lib::plan::{Change, Plan};
+
+#[test]
+fn enable_dry_run() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    symlink(
+        "../../var/lib/extensions.d/foo-2-43-x86-64.raw",
+        root.join("run/extensions/foo.raw"),
+    )
+    .unwrap();
+    let manager = manager(root);
+
+    let plan = manager.plan_enable(Some(&"foo".to_string())).unwrap();
+    assert_eq!(
+        plan.changes,
+        vec![Change::Link {
+            name: "foo".into(),
+            path: "/run/extensions/foo.raw".into(),
+            target: "../../var/lib/extensions.d/foo-3-43-x86-64.raw".into(),
+            previous: Some("../../var/lib/extensions.d/foo-2-43-x86-64.raw".into()),
+        }]
+    );
+    assert!(!root.join("run/extensions/bar.raw").exists());
+    assert_eq!(
+        fs::read_link(root.join("run/extensions/foo.raw")).unwrap(),
+        PathBuf::from("../../var/lib/extensions.d/foo-2-43-x86-64.raw")
+    );
+
+    // Same changes as the real run
+    manager.enable_all().unwrap();
+    assert!(manager.plan_enable(None).unwrap().is_empty());
+    assert_eq!(manager.plan_disable(None).unwrap().changes.len(), 3);
+}
+
+#[test]
+fn clean_dry_run() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let before = list_images(root);
+    let mut manager = manager(root);
+
+    let plan = manager.plan_clean(1).unwrap();
+    assert_eq!(list_images(root), before);
+
+    manager.clean(1).unwrap();
+    let mut removed: Vec<String> = before
+        .into_iter()
+        .filter(|i| !list_images(root).contains(i))
+        .collect();
+    removed.sort();
+    let mut planned: Vec<String> = plan
+        .changes
+        .iter()
+        .map(|c| match c {
+            Change::Delete { path, .. } => path.file_name().unwrap().to_string_lossy().into(),
+            c => panic!("Unexpected change: {c}"),
+        })
+        .collect();
+    planned.sort();
+    assert!(!planned.is_empty());
+    assert_eq!(planned, removed);
+}
+
+#[test]
+fn remove_dry_run() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let before = list_images(root);
+    let manager = manager(root);
+
+    let plan = manager.plan_remove("foo").unwrap();
+    assert!(
+        plan.changes.contains(&Change::Delete {
+            path: "/var/lib/extensions.d/foo-3-43-x86-64.raw".into(),
+            size: fs::metadata(root.join("var/lib/extensions.d/foo-3-43-x86-64.raw"))
+                .unwrap()
+                .len(),
+        })
+    );
+    assert!(
+        plan.changes
+            .iter()
+            .any(|c| matches!(c, Change::Delete { path, .. } if path.ends_with("foo.conf")))
+    );
+    assert_eq!(list_images(root), before);
+    assert!(root.join("etc/sysexts-manager/foo.conf").exists());
+}
+
+#[test]
+fn apply_refuses_unmanaged_files() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let mut manager = manager(root);
+
+    let plan = Plan::new(vec![Change::Delete {
+        path: "/etc/os-release".into(),
+        size: 0,
+    }]);
+    assert!(manager.apply(&plan).is_err());
+    assert!(root.join("etc/os-release").exists());
+
+    let plan = Plan::new(vec![Change::Link {
+        name: "foo".into(),
+        path: "/run/extensions/foo.raw".into(),
+        target: "/etc/os-release".into(),
+        previous: None,
+    }]);
+    assert!(manager.apply(&plan).is_err());
+    assert!(!root.join("run/extensions/foo.raw").exists());
+}
+
+#[test]
+fn apply_refuses_outdated_plan() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let mut manager = manager(root);
+
+    let plan = manager.plan_clean(1).unwrap();
+    let enable = manager.plan_enable(Some(&"foo".to_string())).unwrap();
+    // Changed since the plans were made
+    symlink(
+        "../../var/lib/extensions.d/foo-2-43-x86-64.raw",
+        root.join("run/extensions/foo.raw"),
+    )
+    .unwrap();
+
+    let before = list_images(root);
+    let err = manager.apply(&plan).unwrap_err();
+    assert!(matches!(err, Error::Plan(_)), "{err}");
+    assert_eq!(list_images(root), before);
+
+    let err = manager.apply(&enable).unwrap_err();
+    assert!(matches!(err, Error::Plan(_)), "{err}");
+    assert_eq!(
+        fs::read_link(root.join("run/extensions/foo.raw")).unwrap(),
+        PathBuf::from("../../var/lib/extensions.d/foo-2-43-x86-64.raw")
+    );
+}
@@ -0,0 +1,43 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+use common::{copy_root, manager};
+use tempfile::TempDir;
+
+#[test]
+fn no_config_outside_root() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let outside = TempDir::new().unwrap();
+    fs::write(
+        outside.path().join("evil.conf"),
+        "Name=\"evil\"\nKind=\"latest\"\nUrl=\"https://example.com/evil\"\n",
+    )
+    .unwrap();
+    let _ = fs::remove_dir_all(root.join("run/sysexts-manager"));
+    symlink(outside.path(), root.join("run/sysexts-manager")).unwrap();
+
+    let manager = manager(root);
+    assert!(manager.config("evil").is_none());
+    assert!(manager.config("foo").is_some());
+}
+
+#[test]
+fn no_symlink_outside_root() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let outside = TempDir::new().unwrap();
+    fs::remove_dir_all(root.join("run/extensions")).unwrap();
+    symlink(outside.path(), root.join("run/extensions")).unwrap();
+
+    let manager = manager(root);
+    assert!(manager.enable(&"foo".to_string()).is_err());
+    assert!(manager.enabled("foo").is_empty());
+    assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+}
@@ -0,0 +1,87 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{copy_root, manager};
+use sysexts_manager_lib::arch::Architecture;
+use sysexts_manager_lib::error::Error;
+
+#[test]
+fn status_report() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_pinned"));
+    let root = tmp.path();
+    fs::write(root.join("etc/sysexts-manager/invalid.conf"), "Name = 1\n").unwrap();
+    let manager = manager(root);
+    manager.enable(&"foo".to_string()).unwrap();
+
+    let status = manager.status().unwrap();
+    let names: Vec<&str> = status.sysexts.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["bar", "duck", "foo"]);
+    let foo = &status.sysexts[2];
+    assert_eq!(
+        foo.source,
+        Some(PathBuf::from("/etc/sysexts-manager/foo.conf"))
+    );
+    assert_eq!(foo.enabled.as_deref(), Some("foo-2-43-x86-64.raw"));
+    assert_eq!(foo.version.as_deref(), Some("2"));
+    assert_eq!(foo.images.len(), 3);
+    assert!(foo.error.is_none());
+    assert!(status.sysexts[0].enabled.is_none());
+
+    assert_eq!(status.errors.len(), 1);
+    assert_eq!(
+        status.errors[0].source,
+        PathBuf::from("/etc/sysexts-manager/invalid.conf")
+    );
+}
+
+#[test]
+fn query() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
+    let manager = manager(root);
+
+    assert_eq!(manager.architecture(), &Architecture::x86_64);
+    assert_eq!(manager.version_id(), "43");
+
+    let configs: Vec<(&str, Option<&Path>)> = manager
+        .configs()
+        .into_iter()
+        .map(|(c, p)| (c.Name.as_str(), p))
+        .collect();
+    assert_eq!(
+        configs,
+        vec![
+            ("bar", Some(Path::new("/etc/sysexts-manager/bar.conf"))),
+            ("duck", Some(Path::new("/etc/sysexts-manager/duck.conf"))),
+            ("foo", Some(Path::new("/etc/sysexts-manager/foo.conf"))),
+        ]
+    );
+    assert!(manager.config("foo").is_some());
+    assert!(manager.config("unknown").is_none());
+
+    assert_eq!(manager.images("foo").len(), 3);
+    assert!(manager.images("unknown").is_empty());
+
+    let latest = manager.latest_image("foo", "43").unwrap().unwrap();
+    assert_eq!(latest.path(), "foo-3-43-x86-64.raw");
+    assert!(manager.latest_image("foo", "44").unwrap().is_none());
+    assert!(matches!(
+        manager.latest_image("unknown", "43"),
+        Err(Error::NoConfig(_))
+    ));
+
+    assert!(manager.enabled("foo").is_empty());
+    manager.enable(&"foo".to_string()).unwrap();
+    assert_eq!(
+        manager.enabled("foo"),
+        vec![(
+            PathBuf::from("/run/extensions"),
+            "foo-3-43-x86-64.raw".to_string()
+        )]
+    );
+}
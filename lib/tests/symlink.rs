@@ -1,33 +1,31 @@
 // SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
 // SPDX-License-Identifier: MIT
 
-use std::fs::{read_link, remove_file};
+mod common;
+
+use std::fs::read_link;
 use std::path::{Path, PathBuf};
 
-fn cleanup(root: &Path, sysexts: &Vec<&'static str>) {
-    for s in sysexts {
-        let run_sysexts = root.join("run/extensions").join(format!("{s}.raw"));
-        let _ = remove_file(&run_sysexts);
-        assert!(!run_sysexts.exists());
-    }
-}
+use common::{copy_root, manager};
+use sysexts_manager_lib::error::Error;
 
 fn enable_all(root: &Path) {
-    let mut manager = sysexts_manager_lib::manager::new_with_root(root).unwrap();
-    manager.load_config().unwrap();
-    manager.load_images().unwrap();
+    let manager = manager(root);
     manager.enable_all().unwrap();
 }
 
 fn enable_all_err(root: &Path) {
-    let mut manager = sysexts_manager_lib::manager::new_with_root(root).unwrap();
-    manager.load_config().unwrap();
-    manager.load_images().unwrap();
-    assert!(manager.enable_all().is_err());
+    let manager = manager(root);
+    let err = manager.enable_all().unwrap_err();
+    assert!(matches!(err, Error::NoImage(_)), "{err}");
 }
 
 fn validate_symlink(root: &Path, name: &str, dest: &str) {
-    let run_sysexts = root.join("run/extensions").join(format!("{name}.raw"));
+    validate_symlink_in(root, "run/extensions", name, dest);
+}
+
+fn validate_symlink_in(root: &Path, dir: &str, name: &str, dest: &str) {
+    let run_sysexts = root.join(dir).join(format!("{name}.raw"));
     assert!(run_sysexts.exists());
     assert_eq!(
         read_link(run_sysexts).unwrap(),
@@ -42,60 +40,70 @@ fn validate_no_symlink(root: &Path, name: &str) {
 
 #[test]
 fn valid_version_latest() {
-    let root = Path::new("./test-data/valid_version_latest");
-    let sysexts = vec!["foo", "bar", "duck"];
-    cleanup(root, &sysexts);
+    let tmp = copy_root(Path::new("./test-data/valid_version_latest"));
+    let root = tmp.path();
     enable_all(root);
     validate_symlink(root, "foo", "foo-3-43-x86-64");
     validate_symlink(root, "bar", "bar-20251120-43-x86-64");
     validate_symlink(root, "duck", "duck-1.6.5-43-x86-64");
-    cleanup(root, &sysexts);
+}
+
+#[test]
+fn valid_version_pinned() {
+    let tmp = copy_root(Path::new("./test-data/valid_version_pinned"));
+    let root = tmp.path();
+    enable_all(root);
+    validate_symlink(root, "foo", "foo-2-43-x86-64");
+    validate_symlink(root, "bar", "bar-20251109-43-x86-64");
+    validate_symlink(root, "duck", "duck-1.6.5-43-x86-64");
+}
+
+#[test]
+fn valid_confext() {
+    let tmp = copy_root(Path::new("./test-data/valid_confext"));
+    let root = tmp.path();
+    enable_all(root);
+    validate_symlink(root, "foo", "foo-3-43-x86-64");
+    validate_symlink_in(root, "run/confexts", "baz", "baz-1.1-43-x86-64");
+    validate_no_symlink(root, "baz");
 }
 
 #[test]
 fn valid_current_release() {
-    let root = Path::new("./test-data/valid_current_release");
-    let sysexts = vec!["foo", "bar", "duck"];
-    cleanup(root, &sysexts);
+    let tmp = copy_root(Path::new("./test-data/valid_current_release"));
+    let root = tmp.path();
     enable_all(root);
     validate_symlink(root, "foo", "foo-3-43-x86-64");
     validate_symlink(root, "bar", "bar-20251120-43-x86-64");
     validate_symlink(root, "duck", "duck-1.6.5-43-x86-64");
-    cleanup(root, &sysexts);
 }
 
 #[test]
 fn valid_current_arch() {
-    let root = Path::new("./test-data/valid_current_arch");
-    let sysexts = vec!["foo", "bar", "duck"];
-    cleanup(root, &sysexts);
+    let tmp = copy_root(Path::new("./test-data/valid_current_arch"));
+    let root = tmp.path();
     enable_all(root);
     validate_symlink(root, "foo", "foo-3-43-x86-64");
     validate_symlink(root, "bar", "bar-20251120-43-x86-64");
     validate_symlink(root, "duck", "duck-1.6.5-43-x86-64");
-    cleanup(root, &sysexts);
 }
 
 #[test]
 fn invalid_arch() {
-    let root = Path::new("./test-data/invalid_arch");
-    let sysexts = vec!["foo", "bar", "duck"];
-    cleanup(root, &sysexts);
+    let tmp = copy_root(Path::new("./test-data/invalid_arch"));
+    let root = tmp.path();
     enable_all_err(root);
     validate_no_symlink(root, "foo");
     validate_no_symlink(root, "bar");
     validate_no_symlink(root, "duck");
-    cleanup(root, &sysexts);
 }
 
 #[test]
 fn invalid_release() {
-    let root = Path::new("./test-data/invalid_arch");
-    let sysexts = vec!["foo", "bar", "duck"];
-    cleanup(root, &sysexts);
+    let tmp = copy_root(Path::new("./test-data/invalid_release"));
+    let root = tmp.path();
     enable_all_err(root);
     validate_no_symlink(root, "foo");
     validate_no_symlink(root, "bar");
     validate_no_symlink(root, "duck");
-    cleanup(root, &sysexts);
 }
@@ -0,0 +1,889 @@
+// SPDX-FileCopyrightText: Timothée Ravier <tim@siosm.fr>
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+
+use common::{Recorder, Request, Response, list_images, manager, serve, sign};
+use rustix::fs::{FlockOperation, flock};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sysexts_manager_lib::config::Config;
+use sysexts_manager_lib::error::Error;
+use sysexts_manager_lib::plan::{Change, Plan};
+use sysexts_manager_lib::report::UpdateStatus;
+use tempfile::TempDir;
+
+const IMAGE: &str = "foo-2-43-x86-64.raw";
+
+fn content() -> Vec<u8> {
+    b"foo sysext image".to_vec()
+}
+
+fn sha256sums() -> String {
+    format!("{}  {IMAGE}\n", hex::encode(Sha256::digest(content())))
+}
+
+/// Setup a root with a single sysext using the given URL and mirrors
+fn setup_root(url: &str, mirrors: &[&str]) -> TempDir {
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path();
+    fs::create_dir_all(root.join("etc/sysexts-manager")).unwrap();
+    fs::create_dir_all(root.join("var/lib/extensions.d")).unwrap();
+    fs::write(root.join("etc/os-release"), "ID=fedora\nVERSION_ID=43\n").unwrap();
+    fs::write(root.join("etc/sysexts-manager.conf"), "Retries = 0\n").unwrap();
+    let mirrors = mirrors
+        .iter()
+        .map(|m| format!("\"{m}\""))
+        .collect::<Vec<String>>()
+        .join(", ");
+    fs::write(
+        root.join("etc/sysexts-manager/foo.conf"),
+        format!("Name = \"foo\"\nKind = \"latest\"\nUrl = \"{url}\"\nMirrors = [{mirrors}]\n"),
+    )
+    .unwrap();
+    tmp
+}
+
+fn update(root: &Path) {
+    let mut manager = manager(root);
+    manager.update(false).unwrap();
+}
+
+#[test]
+fn update_from_mirror() {
+    // The primary server has the SHA256SUMS file but is missing the image
+    let primary = serve(|r: &Request| match r.path.as_str() {
+        "/foo/SHA256SUMS" => Response::ok(sha256sums()),
+        _ => Response::status(404),
+    });
+    let mirror = serve(|r: &Request| {
+        if r.path == format!("/foo/{IMAGE}") {
+            Response::ok(content())
+        } else {
+            Response::status(404)
+        }
+    });
+    let tmp = setup_root(&primary, &[&mirror]);
+    let root = tmp.path();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+}
+
+#[test]
+fn no_unverified_sha256sums_from_mirror() {
+    // Without signature verification, SHA256SUMS must only come from the
+    // primary URL
+    let primary = serve(|_: &Request| Response::status(404));
+    let mirror = serve(|r: &Request| match r.path.as_str() {
+        "/foo/SHA256SUMS" => Response::ok(sha256sums()),
+        _ => Response::ok(content()),
+    });
+    let tmp = setup_root(&primary, &[&mirror]);
+    let root = tmp.path();
+    update(root);
+    assert!(!root.join("var/lib/extensions.d").join(IMAGE).exists());
+}
+
+/// Setup a local repository with the SHA256SUMS file and the image
+fn setup_local_repo() -> TempDir {
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path().join("foo");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("SHA256SUMS"), sha256sums()).unwrap();
+    fs::write(dir.join(IMAGE), content()).unwrap();
+    tmp
+}
+
+#[test]
+fn update_from_file_url() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&format!("file://{}", repo.path().display()), &[]);
+    let root = tmp.path();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+}
+
+#[test]
+fn update_from_local_path() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+}
+
+fn digest(content: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(content)))
+}
+
+/// Image manifest with a single layer for the content
+fn manifest(content: &[u8], annotations: serde_json::Value) -> Vec<u8> {
+    json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "digest": digest(b"{}"),
+            "size": 2,
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "digest": digest(content),
+            "size": content.len(),
+        }],
+        "annotations": annotations,
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// Minimal OCI registry serving the given tags for the `foo` repository,
+/// requiring an anonymous token like most public registries
+fn serve_registry(tags: Vec<(&str, Vec<u8>)>, blobs: Vec<Vec<u8>>) -> String {
+    let mut paths: HashMap<String, Vec<u8>> = HashMap::new();
+    let names: Vec<&str> = tags.iter().map(|(t, _)| *t).collect();
+    paths.insert(
+        "/v2/foo/tags/list".into(),
+        json!({ "name": "foo", "tags": names })
+            .to_string()
+            .into_bytes(),
+    );
+    for (tag, manifest) in tags {
+        paths.insert(format!("/v2/foo/manifests/{tag}"), manifest.clone());
+    }
+    for blob in blobs {
+        paths.insert(format!("/v2/foo/blobs/{}", digest(&blob)), blob.clone());
+        // Manifests referenced from indexes
+        paths.insert(format!("/v2/foo/manifests/{}", digest(&blob)), blob);
+    }
+    serve(move |r: &Request| {
+        if r.path.starts_with("/token?") {
+            assert!(r.path.contains("scope=repository%3Afoo%3Apull"));
+            return Response::ok(json!({ "token": "secret" }).to_string());
+        }
+        if r.headers.get("authorization").map(|s| s.as_str()) != Some("Bearer secret") {
+            let host = r.headers.get("host").unwrap();
+            return Response::status(401).header(
+                "WWW-Authenticate",
+                &format!("Bearer realm=\"http://{host}/token\",service=\"registry\""),
+            );
+        }
+        match paths.get(&r.path) {
+            Some(body) => Response::ok(body.clone()),
+            None if r.path == "/v2/" => Response::ok(vec![]),
+            None => Response::status(404),
+        }
+    })
+}
+
+fn oci_url(server: &str) -> String {
+    format!("{}/foo", server.replace("http://", "oci://"))
+}
+
+#[test]
+fn update_from_oci_registry() {
+    let annotations = |version_id: &str| {
+        json!({
+            "io.github.travier.sysexts-manager.version-id": version_id,
+            "io.github.travier.sysexts-manager.architecture": "x86-64",
+        })
+    };
+    let old = b"old foo sysext image".to_vec();
+    let other = b"foo sysext image for another release".to_vec();
+    let server = serve_registry(
+        vec![
+            ("1", manifest(&old, annotations("43"))),
+            ("2", manifest(&content(), annotations("43"))),
+            ("3", manifest(&other, annotations("44"))),
+            // Not a version, ignored
+            ("latest", manifest(&content(), annotations("43"))),
+        ],
+        vec![old, content(), other],
+    );
+    let tmp = setup_root(&oci_url(&server), &[]);
+    let root = tmp.path();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+    assert_eq!(
+        fs::read_dir(root.join("var/lib/extensions.d"))
+            .unwrap()
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn update_from_oci_registry_with_broken_tags() {
+    let annotations = json!({
+        "io.github.travier.sysexts-manager.version-id": "43",
+        "io.github.travier.sysexts-manager.architecture": "x86-64",
+    });
+    let server = serve_registry(
+        vec![
+            ("1", b"not a manifest".to_vec()),
+            ("2", manifest(&content(), annotations)),
+            ("latest", b"not a manifest".to_vec()),
+        ],
+        vec![content()],
+    );
+    let tmp = setup_root(&oci_url(&server), &[]);
+    let root = tmp.path();
+    update(root);
+    assert_eq!(list_images(root), vec![IMAGE]);
+}
+
+#[test]
+fn update_from_oci_image_index() {
+    let annotations = json!({ "io.github.travier.sysexts-manager.version-id": "43" });
+    let arm64 = b"foo sysext image for arm64".to_vec();
+    let x86_64_manifest = manifest(&content(), annotations.clone());
+    let arm64_manifest = manifest(&arm64, annotations);
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": digest(&arm64_manifest),
+                "size": arm64_manifest.len(),
+                "platform": { "architecture": "arm64", "os": "linux" },
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": digest(&x86_64_manifest),
+                "size": x86_64_manifest.len(),
+                "platform": { "architecture": "amd64", "os": "linux" },
+            },
+        ],
+    })
+    .to_string()
+    .into_bytes();
+    let server = serve_registry(
+        vec![("2", index)],
+        vec![content(), arm64, x86_64_manifest, arm64_manifest],
+    );
+    let tmp = setup_root(&oci_url(&server), &[]);
+    let root = tmp.path();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+}
+
+/// HTTP proxy serving the repository for an unresolvable host
+fn serve_proxy() -> String {
+    serve(
+        |r: &Request| match r.path.strip_prefix("http://sysexts.invalid") {
+            Some("/foo/SHA256SUMS") => Response::ok(sha256sums()),
+            Some(p) if p == format!("/foo/{IMAGE}") => Response::ok(content()),
+            _ => Response::status(404),
+        },
+    )
+}
+
+#[test]
+fn update_with_global_proxy() {
+    let proxy = serve_proxy();
+    let tmp = setup_root("http://sysexts.invalid", &[]);
+    let root = tmp.path();
+    fs::write(
+        root.join("etc/sysexts-manager.conf"),
+        format!("Retries = 0\nProxy = \"{proxy}\"\n"),
+    )
+    .unwrap();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+}
+
+#[test]
+fn update_with_sysext_proxy() {
+    let proxy = serve_proxy();
+    let tmp = setup_root("http://sysexts.invalid", &[]);
+    let root = tmp.path();
+    // The sysext specific proxy takes precedence over the global one
+    fs::write(
+        root.join("etc/sysexts-manager.conf"),
+        "Retries = 0\nProxy = \"http://127.0.0.1:1\"\n",
+    )
+    .unwrap();
+    let conf = root.join("etc/sysexts-manager/foo.conf");
+    let mut content = fs::read_to_string(&conf).unwrap();
+    content.push_str(&format!("Proxy = \"{proxy}\"\n"));
+    fs::write(conf, content).unwrap();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert!(image.exists());
+}
+
+/// PEM block with an invalid content, for both certificates and keys
+const INVALID_PEM: &str = "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n";
+
+/// Copy the test certificate and key into the root
+fn setup_tls(root: &Path) {
+    let pki = root.join("etc/pki/sysexts-manager");
+    fs::create_dir_all(&pki).unwrap();
+    for file in ["client.pem", "client.key"] {
+        fs::copy(Path::new("./test-data/tls").join(file), pki.join(file)).unwrap();
+    }
+}
+
+// The following tests use a local repository: they only check that the
+// certificates and keys are loaded when building the client, not the TLS
+// handshakes.
+
+#[test]
+fn ca_certificates_loaded() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    setup_tls(root);
+    add_to_config(
+        root,
+        "CACertificates = \"/etc/pki/sysexts-manager/client.pem\"\n",
+    );
+    let mut manager = manager(root);
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+
+    fs::write(root.join("etc/pki/sysexts-manager/client.pem"), INVALID_PEM).unwrap();
+    fs::remove_file(root.join("var/lib/extensions.d").join(IMAGE)).unwrap();
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    let err = updated[0].error.as_ref().unwrap();
+    assert!(matches!(err, Error::Config(_)), "{err}");
+    assert!(err.to_string().contains("CACertificates"), "{err}");
+}
+
+#[test]
+fn client_certificate_loaded() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    setup_tls(root);
+    add_to_config(
+        root,
+        "ClientCertificate = \"/etc/pki/sysexts-manager/client.pem\"\nClientKey = \"/etc/pki/sysexts-manager/client.key\"\n",
+    );
+    let mut manager = manager(root);
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+
+    fs::write(root.join("etc/pki/sysexts-manager/client.key"), INVALID_PEM).unwrap();
+    fs::remove_file(root.join("var/lib/extensions.d").join(IMAGE)).unwrap();
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    let err = updated[0].error.as_ref().unwrap();
+    assert!(matches!(err, Error::Config(_)), "{err}");
+    assert!(err.to_string().contains("ClientKey"), "{err}");
+}
+
+#[test]
+fn invalid_proxy() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    add_to_config(root, "Proxy = \"http://[invalid\"\n");
+    let mut manager = manager(root);
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    let err = updated[0].error.as_ref().unwrap();
+    assert!(matches!(err, Error::Config(_)), "{err}");
+    assert!(err.to_string().contains("Proxy"), "{err}");
+}
+
+/// Repository only serving requests with the expected Authorization header
+fn serve_private(expected: &'static str) -> String {
+    serve(move |r: &Request| {
+        if r.headers.get("authorization").map(|s| s.as_str()) != Some(expected) {
+            return Response::status(401);
+        }
+        match r.path.as_str() {
+            "/foo/SHA256SUMS" => Response::ok(sha256sums()),
+            p if p == format!("/foo/{IMAGE}") => Response::ok(content()),
+            _ => Response::status(404),
+        }
+    })
+}
+
+fn write_secret(path: &Path, secret: &str, mode: u32) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, secret).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+fn add_to_config(root: &Path, settings: &str) {
+    let conf = root.join("etc/sysexts-manager/foo.conf");
+    let mut content = fs::read_to_string(&conf).unwrap();
+    content.push_str(settings);
+    fs::write(conf, content).unwrap();
+}
+
+#[test]
+fn no_update_without_valid_signature() {
+    let repo = setup_local_repo();
+    let sums = repo.path().join("foo/SHA256SUMS");
+    let keys = TempDir::new().unwrap();
+    let signature = sign(keys.path(), "trusted", &sums);
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    fs::create_dir_all(root.join("etc/sysexts-manager/keys")).unwrap();
+    fs::copy(
+        keys.path().join("trusted.gpg"),
+        root.join("etc/sysexts-manager/keys/default.gpg"),
+    )
+    .unwrap();
+    add_to_config(root, "Verify = true\n");
+    let mut manager = manager(root);
+
+    // Missing signature
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    assert!(updated[0].error.is_some());
+    assert!(list_images(root).is_empty());
+
+    // Signature for other content, even if the image itself is fine
+    fs::write(repo.path().join("foo/SHA256SUMS.gpg"), signature).unwrap();
+    let tampered = format!("{}{}  foo-1-43-x86-64.raw\n", sha256sums(), "0".repeat(64));
+    fs::write(&sums, tampered).unwrap();
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    let err = updated[0].error.as_ref().unwrap();
+    assert!(matches!(err, Error::Signature(_)), "{err}");
+    assert!(list_images(root).is_empty());
+}
+
+#[test]
+fn update_with_credential() {
+    let server = serve_private("Bearer s3cret");
+    let tmp = setup_root(&server, &[]);
+    let root = tmp.path();
+    write_secret(&root.join("etc/credstore/repo"), "s3cret\n", 0o600);
+    add_to_config(root, "Auth = \"credential:repo\"\n");
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+}
+
+#[test]
+fn update_with_basic_auth_file() {
+    let server = serve_private("Basic dXNlcjpwYXNzd29yZA==");
+    let tmp = setup_root(&server, &[]);
+    let root = tmp.path();
+    write_secret(
+        &root.join("etc/sysexts-manager/auth"),
+        "user:password",
+        0o600,
+    );
+    add_to_config(
+        root,
+        "Auth = \"file:/etc/sysexts-manager/auth\"\nAuthType = \"basic\"\n",
+    );
+    update(root);
+    assert!(root.join("var/lib/extensions.d").join(IMAGE).exists());
+}
+
+#[test]
+fn no_credentials_from_readable_file() {
+    let server = serve_private("Bearer s3cret");
+    let tmp = setup_root(&server, &[]);
+    let root = tmp.path();
+    write_secret(&root.join("etc/sysexts-manager/auth"), "s3cret", 0o644);
+    add_to_config(root, "Auth = \"file:/etc/sysexts-manager/auth\"\n");
+    update(root);
+    assert!(!root.join("var/lib/extensions.d").join(IMAGE).exists());
+}
+
+#[test]
+fn no_credentials_sent_to_mirrors() {
+    let primary = serve(|r: &Request| {
+        assert_eq!(r.headers.get("authorization").unwrap(), "Bearer s3cret");
+        match r.path.as_str() {
+            "/foo/SHA256SUMS" => Response::ok(sha256sums()),
+            _ => Response::status(404),
+        }
+    });
+    let mirror = serve(|r: &Request| {
+        if r.headers.contains_key("authorization") {
+            return Response::status(400);
+        }
+        Response::ok(content())
+    });
+    let tmp = setup_root(&primary, &[&mirror]);
+    let root = tmp.path();
+    write_secret(&root.join("etc/credstore/repo"), "s3cret", 0o600);
+    add_to_config(root, "Auth = \"credential:repo\"\n");
+    update(root);
+    assert!(root.join("var/lib/extensions.d").join(IMAGE).exists());
+}
+
+/// Local repository with foo-3 and a store with foo-1 and foo-2, all 1000
+/// bytes
+fn setup_quota(quota: u64) -> (TempDir, TempDir) {
+    let content = vec![0u8; 1000];
+    let repo = TempDir::new().unwrap();
+    let dir = repo.path().join("foo");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("SHA256SUMS"),
+        format!(
+            "{}  foo-3-43-x86-64.raw\n",
+            hex::encode(Sha256::digest(&content))
+        ),
+    )
+    .unwrap();
+    fs::write(dir.join("foo-3-43-x86-64.raw"), &content).unwrap();
+
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    fs::write(
+        root.join("etc/sysexts-manager.conf"),
+        format!("Retries = 0\nStoreQuota = \"{quota}\"\n"),
+    )
+    .unwrap();
+    for image in ["foo-1-43-x86-64.raw", "foo-2-43-x86-64.raw"] {
+        fs::write(root.join("var/lib/extensions.d").join(image), &content).unwrap();
+    }
+    (repo, tmp)
+}
+
+#[test]
+fn update_prunes_old_images_for_quota() {
+    let (_repo, tmp) = setup_quota(2500);
+    let root = tmp.path();
+    update(root);
+    assert_eq!(
+        list_images(root),
+        vec!["foo-2-43-x86-64.raw", "foo-3-43-x86-64.raw"]
+    );
+}
+
+#[test]
+fn update_refused_over_quota() {
+    // Even after pruning, the newest local image does not leave enough space
+    let (_repo, tmp) = setup_quota(1500);
+    let root = tmp.path();
+    update(root);
+    assert_eq!(list_images(root), vec!["foo-2-43-x86-64.raw"]);
+}
+
+#[test]
+fn remove_after_prune() {
+    let (_repo, tmp) = setup_quota(2500);
+    let root = tmp.path();
+    let mut manager = manager(root);
+    manager.update(false).unwrap();
+    let images: Vec<String> = manager.images("foo").iter().map(|i| i.path()).collect();
+    assert_eq!(images.len(), 2);
+    assert!(!images.contains(&"foo-1-43-x86-64.raw".to_string()));
+    manager.plan_remove("foo").unwrap();
+    manager.remove_sysext("foo").unwrap();
+    assert!(list_images(root).is_empty());
+}
+
+#[test]
+fn repair_replaces_image() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    fs::write(&image, b"corrupted").unwrap();
+    let mut manager = manager(root);
+
+    let updated = manager.update(true).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+    assert_eq!(fs::read(&image).unwrap(), content());
+    assert_eq!(manager.images("foo").len(), 1);
+    let status = manager.status().unwrap();
+    assert_eq!(status.sysexts[0].images.len(), 1);
+}
+
+#[test]
+fn drift_skipped_without_repair() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    fs::write(&image, b"corrupted").unwrap();
+    let mut manager = manager(root);
+
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Skipped);
+    assert!(updated[0].error.is_none());
+    assert_eq!(fs::read(&image).unwrap(), b"corrupted");
+    assert_eq!(list_images(root), vec![IMAGE]);
+    assert_eq!(manager.images("foo").len(), 1);
+}
+
+#[test]
+fn repair_replaces_image_atomically() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    fs::write(&image, b"corrupted").unwrap();
+    // Still open, e.g. by a running extension
+    let old = File::open(&image).unwrap();
+    let mut manager = manager(root);
+
+    let updated = manager.update(true).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+    assert_eq!(fs::read(&image).unwrap(), content());
+    // The image was replaced, not overwritten in place
+    assert_ne!(
+        old.metadata().unwrap().ino(),
+        fs::metadata(&image).unwrap().ino()
+    );
+    assert_eq!(old.metadata().unwrap().len(), b"corrupted".len() as u64);
+    assert_eq!(
+        fs::read_dir(root.join("var/lib/extensions.d"))
+            .unwrap()
+            .count(),
+        1
+    );
+    assert_eq!(manager.images("foo").len(), 1);
+}
+
+#[test]
+fn update_sets_image_mode() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    fs::set_permissions(
+        repo.path().join("foo").join(IMAGE),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    update(root);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::metadata(image).unwrap().mode() & 0o7777, 0o644);
+}
+
+#[test]
+fn no_concurrent_download() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&repo.path().display().to_string(), &[]);
+    let root = tmp.path();
+    // Another process is downloading the same image
+    let partial = File::create(
+        root.join("var/lib/extensions.d")
+            .join(format!("{IMAGE}.tmp")),
+    )
+    .unwrap();
+    flock(&partial, FlockOperation::NonBlockingLockExclusive).unwrap();
+    update(root);
+    assert!(!root.join("var/lib/extensions.d").join(IMAGE).exists());
+}
+
+#[test]
+fn concurrent_downloads() {
+    // Hold the first download of the image until we are told to continue
+    let (started, wait_started) = mpsc::channel();
+    let (release, wait_release) = mpsc::channel::<()>();
+    let wait_release = Mutex::new(wait_release);
+    let first = AtomicBool::new(true);
+    let url = serve(move |r: &Request| {
+        if r.path == "/foo/SHA256SUMS" {
+            return Response::ok(sha256sums());
+        }
+        if r.method == "GET" && first.swap(false, Ordering::SeqCst) {
+            started.send(()).unwrap();
+            wait_release.lock().unwrap().recv().unwrap();
+        }
+        Response::ok(content())
+    });
+
+    // Two roots, each with its own instance lock, sharing the same image
+    // store: the store of the outer root links to the one of the inner root
+    let tmp = setup_root(&url, &[]);
+    let root = tmp.path();
+    let inner = root.join("inner");
+    fs::create_dir_all(inner.join("etc/sysexts-manager")).unwrap();
+    for f in [
+        "etc/os-release",
+        "etc/sysexts-manager.conf",
+        "etc/sysexts-manager/foo.conf",
+    ] {
+        fs::copy(root.join(f), inner.join(f)).unwrap();
+    }
+    fs::rename(root.join("var"), inner.join("var")).unwrap();
+    symlink("inner/var", root.join("var")).unwrap();
+
+    let first = thread::spawn(move || manager(&inner).update(false).unwrap());
+    wait_started.recv().unwrap();
+    // The partial download is locked by the first manager
+    let updated = manager(root).update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    let err = updated[0].error.as_ref().unwrap();
+    assert!(matches!(err, Error::Conflict(_)), "{err}");
+
+    release.send(()).unwrap();
+    let updated = first.join().unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+    assert_eq!(list_images(root), vec![IMAGE]);
+    let image = root.join("var/lib/extensions.d").join(IMAGE);
+    assert_eq!(fs::read(image).unwrap(), content());
+    assert!(
+        !root
+            .join("var/lib/extensions.d")
+            .join(format!("{IMAGE}.tmp"))
+            .exists()
+    );
+}
+
+#[test]
+fn update_plan_and_apply() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&format!("file://{}", repo.path().display()), &[]);
+    let root = tmp.path();
+    let mut manager = manager(root);
+
+    let plan = manager.plan_update(false, None).unwrap();
+    assert_eq!(
+        plan.changes,
+        vec![Change::Download {
+            name: "foo".into(),
+            image: IMAGE.into(),
+            hash: hex::encode(Sha256::digest(content())),
+            size: Some(content().len() as u64),
+        }]
+    );
+    assert!(list_images(root).is_empty());
+
+    // Plans go through a file to be reviewed
+    let path = root.join("plan.json");
+    plan.save(&path).unwrap();
+    manager.apply(&Plan::load(&path).unwrap()).unwrap();
+    assert_eq!(list_images(root), vec![IMAGE]);
+    assert!(manager.plan_update(false, None).unwrap().is_empty());
+}
+
+#[test]
+fn apply_checks_hash_from_plan() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&format!("file://{}", repo.path().display()), &[]);
+    let root = tmp.path();
+    let mut manager = manager(root);
+
+    let plan = Plan::new(vec![Change::Download {
+        name: "foo".into(),
+        image: IMAGE.into(),
+        hash: hex::encode(Sha256::digest(b"reviewed content")),
+        size: None,
+    }]);
+    let err = manager.apply(&plan).unwrap_err();
+    assert!(matches!(err, Error::HashMismatch { .. }), "{err}");
+    assert!(list_images(root).is_empty());
+}
+
+#[test]
+fn update_reports_events() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&format!("file://{}", repo.path().display()), &[]);
+    let root = tmp.path();
+    let mut manager = manager(root);
+    let recorder = Recorder::default();
+    manager.set_reporter(Box::new(recorder.clone()));
+
+    let plan = Plan::new(vec![Change::Download {
+        name: "foo".into(),
+        image: IMAGE.into(),
+        hash: hex::encode(Sha256::digest(b"reviewed content")),
+        size: None,
+    }]);
+    assert!(manager.apply(&plan).is_err());
+    manager.update(false).unwrap();
+    manager.enable_all().unwrap();
+    manager.disable_all().unwrap();
+    assert_eq!(
+        recorder.events(),
+        vec![
+            format!("download-started {IMAGE} 0"),
+            format!("verification-failed {IMAGE}"),
+            format!("download-finished {IMAGE} false"),
+            format!("download-started {IMAGE} 0"),
+            format!("download-finished {IMAGE} true"),
+            format!("enabled foo {IMAGE}"),
+            "disabled foo".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn update_results() {
+    let repo = setup_local_repo();
+    let tmp = setup_root(&format!("file://{}", repo.path().display()), &[]);
+    let root = tmp.path();
+    let mut manager = manager(root);
+
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated.len(), 1);
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+    assert_eq!(updated[0].image.as_deref(), Some(IMAGE));
+    assert!(updated[0].error.is_none());
+
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::UpToDate);
+
+    fs::remove_file(repo.path().join("foo/SHA256SUMS")).unwrap();
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    assert!(updated[0].error.is_some());
+}
+
+#[test]
+fn update_results_keep_errors() {
+    let repo = setup_local_repo();
+    fs::write(repo.path().join("foo").join(IMAGE), b"tampered").unwrap();
+    let tmp = setup_root(&format!("file://{}", repo.path().display()), &[]);
+    let root = tmp.path();
+    let mut manager = manager(root);
+
+    let updated = manager.update(false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Failed);
+    let err = updated[0].error.as_ref().unwrap();
+    assert!(matches!(err, Error::HashMismatch { .. }), "{err}");
+    let json = serde_json::to_value(&updated[0]).unwrap();
+    assert_eq!(json["error"], json!(err.to_string()));
+}
+
+#[test]
+fn add_update_and_enable() {
+    let repo = setup_local_repo();
+    let url = format!("file://{}", repo.path().display());
+    let tmp = setup_root(&url, &[]);
+    let root = tmp.path();
+    fs::remove_file(root.join("etc/sysexts-manager/foo.conf")).unwrap();
+    let mut manager = manager(root);
+
+    // All in the same manager, without reloading the configuration or the
+    // images
+    let config: Config = toml::from_str(&format!(
+        "Name = \"foo\"\nKind = \"latest\"\nUrl = \"{url}\"\n"
+    ))
+    .unwrap();
+    manager.add_sysext(&config, &false).unwrap();
+    let updated = manager.update_one("foo", false).unwrap();
+    assert_eq!(updated[0].status, UpdateStatus::Updated);
+    let enabled = manager.enable(&"foo".to_string()).unwrap();
+    assert_eq!(enabled.image, IMAGE);
+    assert_eq!(
+        fs::read_link(root.join("run/extensions/foo.raw")).unwrap(),
+        Path::new("../../var/lib/extensions.d").join(IMAGE)
+    );
+    assert!(matches!(
+        manager.update_one("bar", false).unwrap_err(),
+        Error::NoConfig(_)
+    ));
+}